@@ -1,13 +1,47 @@
-use std::io;
+//! The smallest node Maelstrom accepts: answers `init` with `init_ok` and
+//! every `echo` with an `echo_ok`, one JSON message per line on stdin and
+//! stdout. Logs go to stderr, which Maelstrom keeps out of the protocol.
 
-fn main() {
-    let mut buffer = String::new();
+use serde_json::{json, Value};
+use std::io::{self, BufRead, Write};
 
-    println!("Type and the program will answer with what it received.");
-    loop {
-        let stdin = io::stdin();
-        let _ = stdin.read_line(&mut buffer).expect("Failed to read line");
-        println!("Received: {}", buffer);
-        buffer = String::new();
+fn main() -> io::Result<()> {
+    let mut node_id = String::new();
+    let mut next_msg_id = 1;
+    let mut stdout = io::stdout().lock();
+
+    for line in io::stdin().lock().split(b'\n') {
+        // A line that isn't UTF-8 is as malformed as one that isn't JSON;
+        // only a failing stdin ends the loop
+        let message: Value = match serde_json::from_slice(&line?) {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Ignoring malformed message: {}", e);
+                continue;
+            }
+        };
+        let body = &message["body"];
+        let reply = match body["type"].as_str() {
+            Some("init") => {
+                node_id = body["node_id"].as_str().unwrap_or_default().to_string();
+                eprintln!("Initialized node {}", node_id);
+                json!({"type": "init_ok", "msg_id": next_msg_id, "in_reply_to": body["msg_id"]})
+            }
+            Some("echo") => json!({
+                "type": "echo_ok",
+                "msg_id": next_msg_id,
+                "in_reply_to": body["msg_id"],
+                "echo": body["echo"],
+            }),
+            other => {
+                eprintln!("Ignoring message of type {:?}", other);
+                continue;
+            }
+        };
+        next_msg_id += 1;
+        let reply = json!({"src": node_id, "dest": message["src"], "body": reply});
+        writeln!(stdout, "{}", reply)?;
+        stdout.flush()?;
     }
+    Ok(())
 }
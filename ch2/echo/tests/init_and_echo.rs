@@ -0,0 +1,66 @@
+use serde_json::{json, Value};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+const LINES: [&str; 2] = [
+    r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"echo":"hello"}}"#,
+];
+
+/// Runs echo on `input` until it exits and returns its replies.
+fn run(input: &[u8]) -> Vec<Value> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_echo"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start echo");
+    child.stdin.take().unwrap().write_all(input).unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "echo exited with {}",
+        output.status
+    );
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn answers_init_and_echo() {
+    let replies = run(format!("{}\n{}\n", LINES[0], LINES[1]).as_bytes());
+    assert_eq!(
+        replies,
+        [
+            json!({
+                "src": "n1",
+                "dest": "c0",
+                "body": {"type": "init_ok", "msg_id": 1, "in_reply_to": 1},
+            }),
+            json!({
+                "src": "n1",
+                "dest": "c1",
+                "body": {"type": "echo_ok", "msg_id": 2, "in_reply_to": 2, "echo": "hello"},
+            }),
+        ]
+    );
+}
+
+#[test]
+fn skips_malformed_lines() {
+    let mut input = Vec::new();
+    writeln!(input, "{}", LINES[0]).unwrap();
+    input.extend_from_slice(b"\xff\xfe not utf-8\n");
+    input.extend_from_slice(b"{\"src\": \"c1\", \"body\n");
+    input.extend_from_slice(b"[1, 2, 3]\n");
+    input.extend_from_slice(b"{\"src\":\"c1\",\"dest\":\"n1\",\"body\":7}\n");
+    writeln!(input, "{}", LINES[1]).unwrap();
+
+    let replies = run(&input);
+    let types: Vec<&Value> = replies.iter().map(|reply| &reply["body"]["type"]).collect();
+    assert_eq!(types, ["init_ok", "echo_ok"]);
+}
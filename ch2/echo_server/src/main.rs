@@ -1,35 +1,12 @@
-use serde::de::Error;
+use maelstrom_node::{Body, Message, MsgId, Result};
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Result};
-use std::io;
+use std::sync::Arc;
 
-type NodeId = String;
-type MsgId = u64;
-
-#[derive(Debug)]
-struct Node {
-    node_id: NodeId,
-    next_message_id: MsgId,
-}
-
-impl Node {
-    fn get_next_msg_id(&mut self) -> MsgId {
-        self.next_message_id += 1;
-        self.next_message_id
-    }
-}
+type Node = maelstrom_node::Node<(), MessageBody>;
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 enum MessageBody {
-    #[serde(rename = "init")]
-    Init {
-        msg_id: MsgId,
-        node_id: NodeId,
-        node_ids: Vec<String>,
-    },
-    #[serde(rename = "init_ok")]
-    InitOk { msg_id: MsgId, in_reply_to: MsgId },
     #[serde(rename = "echo")]
     Echo { msg_id: MsgId, echo: String },
     #[serde(rename = "echo_ok")]
@@ -40,100 +17,43 @@ enum MessageBody {
     },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Message {
-    src: NodeId,
-    dest: NodeId,
-    body: MessageBody,
-}
-
-fn parse_init_node() -> Result<(MsgId, NodeId, NodeId, Vec<NodeId>)> {
-    let mut buffer = String::new();
-    let stdin = io::stdin();
-
-    let _ = stdin.read_line(&mut buffer).expect("Failed to read config");
-
-    let config: Message = serde_json::from_str(buffer.as_str())?;
-    eprintln!("Received: {:?}", config);
-
-    let init_request_src = config.src;
-
-    let (init_request_id, node_id, node_ids) = match config.body {
-        MessageBody::Init {
-            msg_id,
-            node_id,
-            node_ids,
-        } => (msg_id, node_id, node_ids),
-
-        _ => {
-            return Err(serde_json::Error::custom(
-                "First message received wasn't init",
-            ))
+impl Body for MessageBody {
+    fn type_tag(&self) -> &'static str {
+        match self {
+            Self::Echo { .. } => "echo",
+            Self::EchoOk { .. } => "echo_ok",
         }
-    };
-    Ok((init_request_id, init_request_src, node_id, node_ids))
-}
-
-fn acknowledge_init_node(
-    node: &mut Node,
-    init_request_id: MsgId,
-    init_ok_tgt: NodeId,
-) -> Result<()> {
-    let response_body = MessageBody::InitOk {
-        msg_id: node.get_next_msg_id(),
-        in_reply_to: init_request_id,
-    };
-
-    let response = serde_json::to_string(&json!(Message {
-        src: node.node_id.clone(),
-        dest: init_ok_tgt,
-        body: response_body,
-    }))
-    .expect("Failed to serialise InitOk response");
-    println!("{}", response);
-    Ok(())
+    }
+    fn msg_id(&self) -> Option<MsgId> {
+        match self {
+            Self::Echo { msg_id, .. } => Some(*msg_id),
+            Self::EchoOk { msg_id, .. } => Some(*msg_id),
+        }
+    }
+    fn in_reply_to(&self) -> Option<MsgId> {
+        match self {
+            Self::EchoOk { in_reply_to, .. } => Some(*in_reply_to),
+            _ => None,
+        }
+    }
 }
 
-fn initialize_node() -> Result<Node> {
-    let (init_request_id, init_request_src, node_id, _node_ids) = parse_init_node()?;
-
-    let mut node = Node {
-        node_id: node_id.clone(),
-        next_message_id: 0,
+fn handle_echo(node: &Arc<Node>, message: &Message<MessageBody>) -> Result<()> {
+    let MessageBody::Echo { echo, .. } = &message.body else {
+        return Err("handle_echo called on different message".into());
     };
-    let _ = acknowledge_init_node(&mut node, init_request_id, init_request_src);
-    eprint!("Initialized Node: {:?}", &node);
-    Ok(node)
+    // Create and stdout the echo response
+    node.reply(message, |in_reply_to| MessageBody::EchoOk {
+        msg_id: node.get_next_msg_id(),
+        echo: echo.clone(),
+        in_reply_to,
+    })
 }
 
 fn main() -> Result<()> {
     // Read the node config
-    let mut node = initialize_node()?;
-
-    loop {
-        let mut buffer = String::new();
-        let stdin = io::stdin();
-        let _ = stdin
-            .read_line(&mut buffer)
-            .expect("Failed to read message.");
-        let message: Message = serde_json::from_str(buffer.as_str())?;
-        match message.body {
-            MessageBody::Echo { msg_id, echo } => {
-                // Create and stdout the echo response
-                let response_body = MessageBody::EchoOk {
-                    msg_id: node.get_next_msg_id(),
-                    echo,
-                    in_reply_to: msg_id,
-                };
-                let response = serde_json::to_string(&json!(Message {
-                    src: node.node_id.clone(),
-                    dest: message.src.clone(),
-                    body: response_body
-                }))
-                .expect("Failed to serizalize Echo Response");
-                println!("{}", response);
-            }
-            _ => continue,
-        }
-    }
+    let node = Node::init(())?;
+    node.register("echo", handle_echo);
+    node.run();
+    Ok(())
 }
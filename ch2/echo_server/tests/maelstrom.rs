@@ -0,0 +1,18 @@
+use maelstrom_node::testing::run_maelstrom;
+use std::path::Path;
+
+#[test]
+fn passes_the_maelstrom_echo_workload() {
+    let Some(run) = run_maelstrom(
+        "echo",
+        env!("CARGO_BIN_EXE_echo_server"),
+        &["--node-count", "1", "--time-limit", "5"],
+        &Path::new(env!("CARGO_TARGET_TMPDIR")).join("maelstrom-echo"),
+    ) else {
+        eprintln!("maelstrom is not on PATH and MAELSTROM_BIN is unset, skipping");
+        return;
+    };
+    let run = run.unwrap();
+    assert!(run.status.success(), "maelstrom failed:\n{}", run.output);
+    assert_eq!(run.valid, Some(true), "{}", run.output);
+}
@@ -0,0 +1,39 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+const LINES: [&str; 5] = [
+    r#"{"src":"c0","dest":"n0","body":{"type":"init","msg_id":1,"node_id":"n0","node_ids":["n0"]}}"#,
+    r#"{"src":"c1","dest":"n0","body":{"type":"frobnicate","msg_id":2}}"#,
+    r#"{"src":"n1","dest":"n0","body":{"type":"init_ok","in_reply_to":1}}"#,
+    "not json at all",
+    r#"{"src":"c1","dest":"n0","body":{"type":"echo","msg_id":3,"echo":"still here"}}"#,
+];
+
+#[test]
+fn keeps_running_after_unknown_and_malformed_messages() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_echo_server"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start echo_server");
+    {
+        let mut stdin = child.stdin.take().unwrap();
+        for line in LINES {
+            writeln!(stdin, "{}", line).unwrap();
+        }
+    }
+
+    let output = child.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "echo_server exited with {}",
+        output.status
+    );
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let echo_ok = stdout
+        .lines()
+        .find(|line| line.contains("echo_ok"))
+        .expect("No echo_ok after the unexpected messages");
+    assert!(echo_ok.contains(r#""in_reply_to":3"#), "{}", echo_ok);
+}
@@ -0,0 +1,57 @@
+use maelstrom_node::{Body, IdGenerator, Message, MsgId, Result, Snowflake};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, OnceLock};
+
+// The generator needs the node id, which is only known once init arrived
+type Node = maelstrom_node::Node<OnceLock<Snowflake>, MessageBody>;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+enum MessageBody {
+    #[serde(rename = "generate")]
+    Generate { msg_id: MsgId },
+    #[serde(rename = "generate_ok")]
+    GenerateOk { id: u64, in_reply_to: MsgId },
+}
+
+impl Body for MessageBody {
+    fn type_tag(&self) -> &'static str {
+        match self {
+            Self::Generate { .. } => "generate",
+            Self::GenerateOk { .. } => "generate_ok",
+        }
+    }
+    fn msg_id(&self) -> Option<MsgId> {
+        match self {
+            Self::Generate { msg_id } => Some(*msg_id),
+            _ => None,
+        }
+    }
+    fn in_reply_to(&self) -> Option<MsgId> {
+        match self {
+            Self::GenerateOk { in_reply_to, .. } => Some(*in_reply_to),
+            _ => None,
+        }
+    }
+}
+
+fn handle_generate(node: &Arc<Node>, message: &Message<MessageBody>) -> Result<()> {
+    let MessageBody::Generate { .. } = message.body else {
+        return Err("handle_generate called on different message".into());
+    };
+    let ids = node.state.get().ok_or("No id generator before init")?;
+    // Snowflake ids embed the node index, so no coordination is needed even
+    // under partitions
+    node.reply(message, |in_reply_to| MessageBody::GenerateOk {
+        id: ids.next_id(),
+        in_reply_to,
+    })
+}
+
+fn main() -> Result<()> {
+    let node = Node::init(OnceLock::new())?;
+    let _ = node.state.set(Snowflake::new(&node.node_id)?);
+    node.register("generate", handle_generate);
+    node.run();
+    Ok(())
+}
@@ -0,0 +1,58 @@
+//! Gzip for large gossip batches, behind the `compression` feature. A
+//! compressed batch carries its values as base64 of the gzipped JSON array.
+
+use crate::Stamped;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{self, Read, Write};
+
+/// Batches with at least this many values are compressed. Smaller ones
+/// don't shrink enough to be worth the CPU.
+pub const COMPRESS_AT: usize = 64;
+
+pub fn compress(messages: &[Stamped]) -> io::Result<String> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    serde_json::to_writer(&mut encoder, messages)?;
+    encoder.flush()?;
+    Ok(STANDARD.encode(encoder.finish()?))
+}
+
+pub fn decompress(compressed: &str) -> io::Result<Vec<Stamped>> {
+    let bytes = STANDARD
+        .decode(compressed)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut json = Vec::new();
+    GzDecoder::new(&bytes[..]).read_to_end(&mut json)?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom_node::{NodeId, VectorClock};
+
+    #[test]
+    fn batches_round_trip_and_shrink() {
+        let messages: Vec<Stamped> = (1..=10_000)
+            .map(|i| {
+                let origin = NodeId::from(format!("n{}", i % 25));
+                let mut clock = VectorClock::new();
+                clock.increment(&origin);
+                Stamped(origin, i / 25 + 1, i as i64, clock)
+            })
+            .collect();
+        let plain = serde_json::to_string(&messages).unwrap();
+        let compressed = compress(&messages).unwrap();
+        assert_eq!(decompress(&compressed).unwrap(), messages);
+        assert!(
+            compressed.len() * 3 < plain.len(),
+            "{} bytes compressed from {}",
+            compressed.len(),
+            plain.len()
+        );
+        assert!(decompress("not base64!").is_err());
+    }
+}
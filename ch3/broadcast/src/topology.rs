@@ -0,0 +1,190 @@
+use maelstrom_node::NodeId;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+
+pub type Topology = HashMap<NodeId, Vec<NodeId>>;
+
+/// Which edges a node forwards broadcasts along, chosen with the
+/// `MAELSTROM_FORWARDING` environment variable (`tree` or `topology`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Forwarding {
+    /// Only the edges of a spanning tree of the given topology.
+    SpanningTree,
+    /// Every neighbor Maelstrom's topology lists.
+    Topology,
+}
+
+impl Forwarding {
+    pub fn from_env() -> Self {
+        match std::env::var("MAELSTROM_FORWARDING").as_deref() {
+            Ok("topology") => Forwarding::Topology,
+            _ => Forwarding::SpanningTree,
+        }
+    }
+}
+
+/// What a `topology` message does to the topology a node already has,
+/// chosen with `MAELSTROM_TOPOLOGY_UPDATE` (`replace` or `merge`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopologyUpdate {
+    /// The new map is the whole topology.
+    Replace,
+    /// The new map adds links to the known ones, see [`merge`].
+    Merge,
+}
+
+impl TopologyUpdate {
+    pub fn from_env() -> Self {
+        match std::env::var("MAELSTROM_TOPOLOGY_UPDATE").as_deref() {
+            Ok("merge") => TopologyUpdate::Merge,
+            _ => TopologyUpdate::Replace,
+        }
+    }
+}
+
+/// Adds every link in `update` to `known`, keeping the links `known`
+/// already had. Applying the same update twice changes nothing.
+pub fn merge(known: &mut Topology, update: &Topology) {
+    for (node, neighbors) in update {
+        let known_neighbors = known.entry(node.clone()).or_default();
+        for neighbor in neighbors {
+            if !known_neighbors.contains(neighbor) {
+                known_neighbors.push(neighbor.clone());
+            }
+        }
+    }
+}
+
+/// Reduces `topology` to a BFS spanning tree rooted at the smallest node id,
+/// so every node derives the same tree from the same map. Edges are treated
+/// as undirected. Returns `None` if the graph is not connected.
+pub fn spanning_tree(topology: &Topology) -> Option<Topology> {
+    let mut edges: BTreeMap<&NodeId, BTreeSet<&NodeId>> = BTreeMap::new();
+    for (node, neighbors) in topology {
+        edges.entry(node).or_default();
+        for neighbor in neighbors {
+            edges.entry(node).or_default().insert(neighbor);
+            edges.entry(neighbor).or_default().insert(node);
+        }
+    }
+    let root = *edges.keys().next()?;
+
+    let mut tree = Topology::new();
+    let mut visited = HashSet::from([root]);
+    let mut queue = VecDeque::from([root]);
+    while let Some(node) = queue.pop_front() {
+        for &neighbor in &edges[node] {
+            if visited.insert(neighbor) {
+                tree.entry(node.clone()).or_default().push(neighbor.clone());
+                tree.entry(neighbor.clone()).or_default().push(node.clone());
+                queue.push_back(neighbor);
+            }
+        }
+    }
+    (visited.len() == edges.len()).then_some(tree)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topology(links: &[(&str, &[&str])]) -> Topology {
+        links
+            .iter()
+            .map(|(node, neighbors)| {
+                let neighbors = neighbors.iter().copied().map(NodeId::from).collect();
+                (NodeId::from(*node), neighbors)
+            })
+            .collect()
+    }
+
+    /// The `side` by `side` grid Maelstrom's `--topology grid` sends.
+    fn grid(side: usize) -> Topology {
+        let id = |row: usize, column: usize| NodeId::from(format!("n{}", row * side + column));
+        let mut grid = Topology::new();
+        for row in 0..side {
+            for column in 0..side {
+                let mut neighbors = Vec::new();
+                if row > 0 {
+                    neighbors.push(id(row - 1, column));
+                }
+                if row + 1 < side {
+                    neighbors.push(id(row + 1, column));
+                }
+                if column > 0 {
+                    neighbors.push(id(row, column - 1));
+                }
+                if column + 1 < side {
+                    neighbors.push(id(row, column + 1));
+                }
+                grid.insert(id(row, column), neighbors);
+            }
+        }
+        grid
+    }
+
+    #[test]
+    fn disconnected_maps_have_no_spanning_tree() {
+        let islands = topology(&[
+            ("n0", &["n1"]),
+            ("n1", &["n0"]),
+            ("n2", &["n3"]),
+            ("n3", &["n2"]),
+        ]);
+        assert_eq!(spanning_tree(&islands), None);
+        assert_eq!(spanning_tree(&Topology::new()), None);
+    }
+
+    #[test]
+    fn spanning_tree_of_a_grid_reaches_every_node_over_n_minus_1_edges() {
+        let grid = grid(5);
+        let tree = spanning_tree(&grid).unwrap();
+
+        let nodes: HashSet<&NodeId> = tree.keys().collect();
+        assert_eq!(nodes, grid.keys().collect());
+        let ends: usize = tree.values().map(Vec::len).sum();
+        assert_eq!(ends / 2, grid.len() - 1);
+        for (node, neighbors) in &tree {
+            for neighbor in neighbors {
+                assert!(
+                    grid[node].contains(neighbor),
+                    "{} - {} is not in the grid",
+                    node,
+                    neighbor
+                );
+                assert!(tree[neighbor].contains(node));
+            }
+        }
+    }
+
+    #[test]
+    fn every_node_derives_the_same_tree_from_the_same_map() {
+        let grid = grid(4);
+        // The same links, listed in another order, in a map with its own
+        // iteration order
+        let mut links: Vec<_> = grid.clone().into_iter().collect();
+        links.sort_by(|(a, _), (b, _)| b.cmp(a));
+        let reversed: Topology = links
+            .into_iter()
+            .map(|(node, mut neighbors)| {
+                neighbors.reverse();
+                (node, neighbors)
+            })
+            .collect();
+        assert_eq!(spanning_tree(&grid), spanning_tree(&reversed));
+    }
+
+    #[test]
+    fn merging_an_update_twice_changes_nothing() {
+        let mut known = topology(&[("n0", &["n1"]), ("n1", &["n0"])]);
+        let update = topology(&[("n1", &["n0", "n2"]), ("n2", &["n1"])]);
+        merge(&mut known, &update);
+        let once = known.clone();
+        merge(&mut known, &update);
+
+        assert_eq!(known, once);
+        assert_eq!(
+            known,
+            topology(&[("n0", &["n1"]), ("n1", &["n0", "n2"]), ("n2", &["n1"])])
+        );
+    }
+}
@@ -0,0 +1,18 @@
+use maelstrom_node::testing::run_maelstrom;
+use std::path::Path;
+
+#[test]
+fn passes_the_maelstrom_broadcast_workload() {
+    let Some(run) = run_maelstrom(
+        "broadcast",
+        env!("CARGO_BIN_EXE_broadcast"),
+        &["--node-count", "5", "--time-limit", "10", "--rate", "10"],
+        &Path::new(env!("CARGO_TARGET_TMPDIR")).join("maelstrom-broadcast"),
+    ) else {
+        eprintln!("maelstrom is not on PATH and MAELSTROM_BIN is unset, skipping");
+        return;
+    };
+    let run = run.unwrap();
+    assert!(run.status.success(), "maelstrom failed:\n{}", run.output);
+    assert_eq!(run.valid, Some(true), "{}", run.output);
+}
@@ -0,0 +1,32 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const INIT: &str = r#"{"src":"c0","dest":"n0","body":{"type":"init","msg_id":1,"node_id":"n0","node_ids":["n0"]}}"#;
+
+#[test]
+fn exits_when_stdin_is_closed() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_broadcast"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start broadcast");
+    {
+        let mut stdin = child.stdin.take().unwrap();
+        writeln!(stdin, "{}", INIT).unwrap();
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait().unwrap() {
+            assert!(status.success(), "broadcast exited with {}", status);
+            return;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+    panic!("broadcast kept running after stdin was closed");
+}
@@ -0,0 +1,247 @@
+use anyhow::{Result, anyhow, bail};
+use maelstrom_node::kv::SEQ_KV;
+use maelstrom_node::{
+    Body, ErrorCode, KvBody, MaelstromError, Message, MsgId, NodeId, RetryPolicy, lock,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+type Node = maelstrom_node::Node<State, MessageBody>;
+
+const PERSIST_INTERVAL: Duration = Duration::from_millis(100);
+const KV_RETRY: RetryPolicy = RetryPolicy {
+    timeout: Duration::from_millis(500),
+    max_retries: 2,
+};
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+enum MessageBody {
+    #[serde(rename = "add")]
+    Add { msg_id: MsgId, delta: u64 },
+    #[serde(rename = "add_ok")]
+    AddOk { in_reply_to: MsgId },
+    #[serde(rename = "read")]
+    Read { msg_id: MsgId },
+    // Replies from seq-kv. A client `read_ok` has the same shape as the
+    // service's, so it is sent as a `KvBody::ReadOk` too.
+    #[serde(untagged)]
+    Kv(KvBody),
+}
+
+impl Body for MessageBody {
+    fn type_tag(&self) -> &'static str {
+        match self {
+            Self::Add { .. } => "add",
+            Self::AddOk { .. } => "add_ok",
+            Self::Read { .. } => "read",
+            Self::Kv(body) => body.type_tag(),
+        }
+    }
+    fn msg_id(&self) -> Option<MsgId> {
+        match self {
+            Self::Add { msg_id, .. } => Some(*msg_id),
+            Self::Read { msg_id } => Some(*msg_id),
+            Self::Kv(body) => body.msg_id(),
+            _ => None,
+        }
+    }
+    fn in_reply_to(&self) -> Option<MsgId> {
+        match self {
+            Self::AddOk { in_reply_to } => Some(*in_reply_to),
+            Self::Kv(body) => body.in_reply_to(),
+            _ => None,
+        }
+    }
+}
+
+/// Each node counts its own adds and is the only writer of its key in
+/// seq-kv. Reads sum every node's key.
+#[derive(Default)]
+struct State {
+    count: Mutex<u64>,
+    persisted: Mutex<Persisted>,
+}
+
+#[derive(Default)]
+struct Persisted {
+    value: u64,
+    // Only one write is in flight at a time, so writes can't be reordered
+    in_flight: bool,
+}
+
+/// A client read waiting for the other nodes' counts from seq-kv.
+struct PendingRead {
+    client: NodeId,
+    in_reply_to: MsgId,
+    remaining: usize,
+    sum: u64,
+}
+
+/// Writes our count to seq-kv if it changed since the last acknowledged write.
+fn persist_count(node: &Arc<Node>) {
+    let count = *lock(&node.state.count);
+    {
+        let mut persisted = lock(&node.state.persisted);
+        if persisted.in_flight || persisted.value == count {
+            return;
+        }
+        persisted.in_flight = true;
+    }
+    let sent = node.rpc_with_timeout(
+        &NodeId::from(SEQ_KV),
+        |msg_id| {
+            MessageBody::Kv(KvBody::Write {
+                msg_id,
+                key: node.node_id.to_string(),
+                value: count,
+            })
+        },
+        KV_RETRY,
+        Box::new(move |node, response| {
+            let mut persisted = lock(&node.state.persisted);
+            persisted.in_flight = false;
+            if let Ok(Message {
+                body: MessageBody::Kv(KvBody::WriteOk { .. }),
+                ..
+            }) = response
+            {
+                persisted.value = count;
+            }
+            Ok(())
+        }),
+        Box::new(|node| {
+            lock(&node.state.persisted).in_flight = false;
+        }),
+    );
+    if let Err(e) = sent {
+        node.log_error(&format!("Failed to persist count: {}", e));
+        lock(&node.state.persisted).in_flight = false;
+    }
+    let _ = node.flush();
+}
+
+/// Adds one node's count to a pending read and answers the client once
+/// every node has been accounted for.
+fn complete_read(node: &Arc<Node>, pending: &Mutex<PendingRead>, value: u64) {
+    let mut pending = lock(pending);
+    pending.sum += value;
+    pending.remaining -= 1;
+    if pending.remaining == 0 {
+        let response_body = MessageBody::Kv(KvBody::ReadOk {
+            in_reply_to: pending.in_reply_to,
+            value: pending.sum,
+        });
+        if let Err(e) = node.send(&pending.client, response_body) {
+            node.log_error(&format!("Failed to answer read: {}", e));
+        }
+        let _ = node.flush();
+    }
+}
+
+fn handle_read(node: &Arc<Node>, message: &Message<MessageBody>) -> Result<()> {
+    let MessageBody::Read { msg_id } = message.body else {
+        bail!("handle_read called on different message");
+    };
+    let own_count = *lock(&node.state.count);
+    let peers: Vec<&NodeId> = node.peers().collect();
+    let pending = Arc::new(Mutex::new(PendingRead {
+        client: message.src.clone(),
+        in_reply_to: msg_id,
+        remaining: peers.len() + 1,
+        sum: 0,
+    }));
+    // Our own count is always fresher locally than in seq-kv
+    complete_read(node, &pending, own_count);
+
+    for peer in peers {
+        let on_reply = Arc::clone(&pending);
+        let on_timeout = Arc::clone(&pending);
+        node.rpc_with_timeout(
+            &NodeId::from(SEQ_KV),
+            |msg_id| {
+                MessageBody::Kv(KvBody::Read {
+                    msg_id,
+                    key: peer.to_string(),
+                })
+            },
+            KV_RETRY,
+            Box::new(move |node, response| {
+                let value = match response.map(|response| &response.body) {
+                    Ok(MessageBody::Kv(KvBody::ReadOk { value, .. })) => *value,
+                    // The peer has not persisted anything yet
+                    Err(MaelstromError {
+                        code: ErrorCode::KeyDoesNotExist,
+                        ..
+                    }) => 0,
+                    reply => {
+                        node.log_warn(&format!("Unexpected reply to read: {:?}", reply));
+                        0
+                    }
+                };
+                complete_read(node, &on_reply, value);
+                Ok(())
+            }),
+            Box::new(move |node| {
+                node.log_warn("Read from seq-kv timed out, counting peer as 0");
+                complete_read(node, &on_timeout, 0);
+            }),
+        )
+        .map_err(|e| anyhow!(e))?;
+    }
+    Ok(())
+}
+
+fn handle_add(node: &Arc<Node>, message: &Message<MessageBody>) -> Result<()> {
+    let MessageBody::Add { delta, .. } = message.body else {
+        bail!("handle_add called on different message");
+    };
+    // A redelivered add was already counted, it only needs its ack again
+    if !node.is_duplicate(message) {
+        *lock(&node.state.count) += delta;
+    }
+    node.reply(message, |in_reply_to| MessageBody::AddOk { in_reply_to })
+        .map_err(|e| anyhow!(e))
+}
+
+fn main() -> Result<()> {
+    let node = Node::init(State::default()).map_err(|e| anyhow!(e))?;
+    node.register("add", handle_add);
+    node.register("read", handle_read);
+    node.every(PERSIST_INTERVAL, Box::new(persist_count));
+    node.run();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom_node::testing::{init_line, run_lines};
+
+    #[test]
+    fn redelivered_add_is_acked_but_counted_once() {
+        let init = init_line("n1", &["n1"]);
+        let add = r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":1,"delta":3}}"#;
+        let output = run_lines(
+            State::default(),
+            |node: &Arc<Node>| {
+                node.register("add", handle_add);
+                node.register("read", handle_read);
+            },
+            &[
+                &init,
+                add,
+                add,
+                r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":2}}"#,
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            output.iter().filter(|line| line.contains("add_ok")).count(),
+            2
+        );
+        assert!(output[3].contains(r#""value":3"#), "{}", output[3]);
+    }
+}
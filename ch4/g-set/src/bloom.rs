@@ -0,0 +1,108 @@
+//! A Bloom filter over set elements, gossiped as a digest of a set in place
+//! of the set itself. It goes on the wire as base64 of its bits.
+//!
+//! A filter never misses an element that was inserted, but may claim one
+//! that wasn't; at BITS_PER_ELEMENT that happens for about 1% of elements.
+//! Filters built with a different seed hash every element differently, so
+//! an element one round's filter wrongly claims is most likely caught by
+//! the next.
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+const BITS_PER_ELEMENT: usize = 10;
+const HASHES: u64 = 7;
+// Even an empty set gets a few bytes, so a filter is never empty
+const MIN_BYTES: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    seed: u64,
+}
+
+impl BloomFilter {
+    /// An empty filter sized for `elements` elements.
+    pub fn with_capacity(elements: usize, seed: u64) -> Self {
+        let bytes = (elements * BITS_PER_ELEMENT).div_ceil(8).max(MIN_BYTES);
+        BloomFilter {
+            bits: vec![0; bytes],
+            seed,
+        }
+    }
+
+    /// A filter holding `elements`.
+    pub fn of(elements: &[u64], seed: u64) -> Self {
+        let mut filter = BloomFilter::with_capacity(elements.len(), seed);
+        for &element in elements {
+            filter.insert(element);
+        }
+        filter
+    }
+
+    /// The filter `encoded` with `encode` from the same seed, `None` if it
+    /// isn't valid base64 or has no bits.
+    pub fn decode(encoded: &str, seed: u64) -> Option<Self> {
+        let bits = STANDARD.decode(encoded).ok()?;
+        (!bits.is_empty()).then_some(BloomFilter { bits, seed })
+    }
+
+    pub fn encode(&self) -> String {
+        STANDARD.encode(&self.bits)
+    }
+
+    pub fn insert(&mut self, element: u64) {
+        for bit in self.positions(element) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Whether `element` may have been inserted. `false` is certain.
+    pub fn contains(&self, element: u64) -> bool {
+        self.positions(element)
+            .all(|bit| self.bits[bit / 8] & 1 << (bit % 8) != 0)
+    }
+
+    // Double hashing: the halves of one 64-bit hash give every probe
+    fn positions(&self, element: u64) -> impl Iterator<Item = usize> + use<> {
+        let mut hasher = DefaultHasher::new();
+        (self.seed, element).hash(&mut hasher);
+        let hash = hasher.finish();
+        let (first, step) = (hash & 0xffff_ffff, hash >> 32 | 1);
+        let len = self.bits.len() as u64 * 8;
+        (0..HASHES).map(move |i| (first.wrapping_add(i.wrapping_mul(step)) % len) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inserted_elements_are_always_contained() {
+        let elements: Vec<u64> = (0..1000).map(|i| i * 7919).collect();
+        let filter = BloomFilter::of(&elements, 3);
+        assert!(elements.iter().all(|&element| filter.contains(element)));
+        assert!(!BloomFilter::with_capacity(0, 3).contains(1));
+    }
+
+    #[test]
+    fn few_other_elements_are_claimed() {
+        let filter = BloomFilter::of(&(0..1000).collect::<Vec<_>>(), 1);
+        let claimed = (1000..11_000)
+            .filter(|&other| filter.contains(other))
+            .count();
+        assert!(claimed < 300, "{} of 10000 claimed", claimed);
+    }
+
+    #[test]
+    fn filters_round_trip_through_base64() {
+        let filter = BloomFilter::of(&[1, 2, 3], 9);
+        let decoded = BloomFilter::decode(&filter.encode(), 9).unwrap();
+        assert_eq!(decoded, filter);
+        assert!(decoded.contains(2));
+        assert_eq!(BloomFilter::decode("", 9), None);
+        assert_eq!(BloomFilter::decode("not base64!", 9), None);
+    }
+}
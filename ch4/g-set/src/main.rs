@@ -1,174 +1,1070 @@
+mod bloom;
+
 use anyhow::{Result, anyhow, bail};
+use bloom::BloomFilter;
+use crossbeam::channel::bounded;
+use maelstrom_node::{
+    Body, Callback, Checkpoint, Message, MsgId, NodeId, ReceiveError, RetryPolicy, TimeoutFn,
+    impl_ack, lock,
+};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use std::error::Error as StdError;
-use std::io::Write;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant};
 
-type NodeId = u64;
-type MsgId = u64;
 type MessageContent = u64;
+type Node = maelstrom_node::Node<State, MessageBody>;
 
-type HandlerFn = Box<
-    dyn Fn(&Arc<Node>, &Message) -> std::result::Result<(), Box<dyn StdError>> + Send + 'static,
->;
-
-#[derive(Serialize, Deserialize, Debug)]
-struct Message {
-    src: NodeId,
-    dest: NodeId,
-    body: MessageBody,
-}
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(200);
+// A peer silent for this long was probably cut off from us. When it is
+// heard from again, its whole set is pulled right away.
+const REJOIN_AFTER: Duration = Duration::from_secs(1);
+const SYNC_RETRY: RetryPolicy = RetryPolicy {
+    timeout: Duration::from_secs(1),
+    max_retries: 0,
+};
+// Worker threads handling messages, and how many messages each one's queue
+// holds before the reader blocks, as in broadcast
+const WORKERS: usize = 4;
+const QUEUE_SIZE: usize = 1024;
+// Elements per read_page_ok
+const PAGE_SIZE: usize = 1000;
+// Set to 1 to check on every read that nothing gossiped has disappeared
+const CHECK_READS_ENV: &str = "MAELSTROM_ALL_READS_CONSISTENT";
+// Set to 1 to checksum gossip and sync payloads and log mismatches
+const CHECKSUMS_ENV: &str = "MAELSTROM_CHECKSUMS";
+// Set to 1 to gossip digests of the set instead of the set itself
+const DIGESTS_ENV: &str = "MAELSTROM_DIGEST_GOSSIP";
 
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
 enum MessageBody {
-    #[serde(rename = "init")]
-    Init { msg_id: MsgId, node_id: NodeId },
-    #[serde(rename = "init_ok")]
-    InitOk { in_reply_to: MsgId },
     #[serde(rename = "add")]
-    Add { element: u64, msg_id: MsgId },
+    Add {
+        element: MessageContent,
+        msg_id: MsgId,
+    },
     #[serde(rename = "add_ok")]
     AddOk { in_reply_to: MsgId },
+    // Adds acknowledged by this node are always in its reads. Adds made on
+    // another node only show up once gossip brings them, unless `sync` is
+    // set: then the node first pulls every peer's set, see handle_read.
     #[serde(rename = "read")]
-    Read { msg_id: MsgId },
+    Read {
+        msg_id: MsgId,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        sync: bool,
+    },
     #[serde(rename = "read_ok")]
     ReadOk {
         in_reply_to: MsgId,
-        value: Vec<u64>,
-        msg_id: u64,
+        value: Vec<MessageContent>,
+        msg_id: MsgId,
     },
+    // The set in ascending pages of PAGE_SIZE elements. Start without a
+    // cursor and pass each reply's `next_cursor` until it is missing. The
+    // cursor is the first element of the next page rather than an offset,
+    // so elements added between calls never shift a page.
+    #[serde(rename = "read_page")]
+    ReadPage {
+        msg_id: MsgId,
+        #[serde(default)]
+        cursor: MessageContent,
+    },
+    #[serde(rename = "read_page_ok")]
+    ReadPageOk {
+        in_reply_to: MsgId,
+        values: Vec<MessageContent>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        next_cursor: Option<MessageContent>,
+    },
+    // Our full set, sent to every other node. Lost gossip is harmless since
+    // the next round carries everything again, so it is never acknowledged.
+    #[serde(rename = "gossip")]
+    Gossip {
+        msg_id: MsgId,
+        values: Vec<MessageContent>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        checksum: Option<u64>,
+    },
+    // Asks a peer for its full set, on top of the periodic push
+    #[serde(rename = "sync")]
+    SyncRequest { msg_id: MsgId },
+    #[serde(rename = "sync_ok")]
+    SyncResponse {
+        in_reply_to: MsgId,
+        values: Vec<MessageContent>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        checksum: Option<u64>,
+    },
+    // In place of gossip when digests are on: a Bloom filter of our set,
+    // base64 encoded and hashed with `seed`. The peer answers with the
+    // elements the filter doesn't contain, which we are missing for sure.
+    #[serde(rename = "digest")]
+    Digest {
+        msg_id: MsgId,
+        filter_bytes: String,
+        seed: u64,
+    },
+    #[serde(rename = "digest_ok")]
+    DigestOk {
+        in_reply_to: MsgId,
+        values: Vec<MessageContent>,
+    },
+}
+
+impl_ack!(MessageBody { Add => AddOk });
+
+impl Body for MessageBody {
+    fn type_tag(&self) -> &'static str {
+        match self {
+            Self::Add { .. } => "add",
+            Self::AddOk { .. } => "add_ok",
+            Self::Read { .. } => "read",
+            Self::ReadOk { .. } => "read_ok",
+            Self::ReadPage { .. } => "read_page",
+            Self::ReadPageOk { .. } => "read_page_ok",
+            Self::Gossip { .. } => "gossip",
+            Self::SyncRequest { .. } => "sync",
+            Self::SyncResponse { .. } => "sync_ok",
+            Self::Digest { .. } => "digest",
+            Self::DigestOk { .. } => "digest_ok",
+        }
+    }
+    fn msg_id(&self) -> Option<MsgId> {
+        match self {
+            Self::Add { msg_id, .. } => Some(*msg_id),
+            Self::Read { msg_id, .. } => Some(*msg_id),
+            Self::ReadPage { msg_id, .. } => Some(*msg_id),
+            Self::ReadOk { msg_id, .. } => Some(*msg_id),
+            Self::Gossip { msg_id, .. } => Some(*msg_id),
+            Self::SyncRequest { msg_id } => Some(*msg_id),
+            Self::Digest { msg_id, .. } => Some(*msg_id),
+            _ => None,
+        }
+    }
+    fn in_reply_to(&self) -> Option<MsgId> {
+        match self {
+            Self::AddOk { in_reply_to } => Some(*in_reply_to),
+            Self::ReadOk { in_reply_to, .. } => Some(*in_reply_to),
+            Self::ReadPageOk { in_reply_to, .. } => Some(*in_reply_to),
+            Self::SyncResponse { in_reply_to, .. } => Some(*in_reply_to),
+            Self::DigestOk { in_reply_to, .. } => Some(*in_reply_to),
+            _ => None,
+        }
+    }
 }
 
-struct Node {
-    node_id: NodeId,
-    messages: Arc<Mutex<HashSet<MessageContent>>>,
-    stdin: Arc<Mutex<std::io::Stdin>>,
-    stdout: Arc<Mutex<std::io::Stdout>>,
-    stderr: Arc<Mutex<std::io::Stderr>>,
-    callbacks: Arc<Mutex<HashMap<MsgId, HandlerFn>>>,
-    next_message_id: AtomicU64,
+#[derive(Default)]
+struct State {
+    // Ordered, so pages are read straight from the cursor on
+    messages: Arc<Mutex<BTreeSet<MessageContent>>>,
+    // What the last gossip round sent, kept only while reads are checked.
+    // A grow-only set has to stay a superset of it.
+    last_gossiped: Option<Mutex<Vec<MessageContent>>>,
+    // When each peer's gossip last arrived
+    last_heard: Mutex<HashMap<NodeId, Instant>>,
+    // Whether gossip and sync carry checksums of their values
+    checksums: bool,
+    // Whether gossip rounds send digests rather than the full set
+    digests: bool,
 }
 
-impl Node {
-    fn new(node_id: NodeId) -> Node {
-        Node {
-            node_id,
-            messages: Arc::new(Mutex::new(HashSet::new())),
-            stdin: Arc::new(Mutex::new(std::io::stdin())),
-            stdout: Arc::new(Mutex::new(std::io::stdout())),
-            stderr: Arc::new(Mutex::new(std::io::stderr())),
-            callbacks: Arc::new(Mutex::new(HashMap::new())),
-            next_message_id: AtomicU64::new(0),
+impl State {
+    fn checking_reads() -> Self {
+        State {
+            last_gossiped: Some(Mutex::new(Vec::new())),
+            ..State::default()
         }
     }
 
-    fn every(&self, dt: Duration, f: HandlerFn) -> Result<()> {
-        todo!()
+    /// Notes gossip from `peer` at `now`. Returns whether the peer is back
+    /// after being silent for longer than REJOIN_AFTER.
+    fn heard_from(&self, peer: &NodeId, now: Instant) -> bool {
+        lock(&self.last_heard)
+            .insert(peer.clone(), now)
+            .is_some_and(|last| now.duration_since(last) > REJOIN_AFTER)
     }
 
-    fn receive(&self) -> Result<Message> {
-        let mut buffer = String::new();
-        let stdin = self.stdin.lock().unwrap();
-        let _ = stdin
-            .read_line(&mut buffer)
-            .expect("Node failed to read stdin");
-        let message: Message = serde_json::from_str(buffer.as_str())?;
-        Ok(message)
+    /// The checksum to send along with `values`, if checksums are on.
+    fn checksum_for(&self, values: &[MessageContent]) -> Option<u64> {
+        self.checksums.then(|| checksum(values))
     }
 
-    fn send(&self, dest: &NodeId, body: MessageBody) -> Result<()> {
-        let message = Message {
-            src: self.node_id,
-            dest: *dest,
-            body,
-        };
-        let jsonified = serde_json::to_string(&message).expect("Failed to serialize message");
-        let _ = match self.stdout.lock() {
-            Ok(mut stdout_guard) => {
-                writeln!(stdout_guard, "{}", jsonified)
-            }
-            Err(e) => bail!("Failed to capture lock on stdout for sending: {}", e),
-        };
-        Ok(())
+    fn record_gossiped(&self, values: &[MessageContent]) {
+        if let Some(last_gossiped) = &self.last_gossiped {
+            *lock(last_gossiped) = values.to_vec();
+        }
+    }
+
+    /// Elements of the last gossiped snapshot missing from `current`, or
+    /// `None` when reads aren't checked.
+    fn missing_since_gossip(&self, current: &[MessageContent]) -> Option<Vec<MessageContent>> {
+        let last_gossiped = lock(self.last_gossiped.as_ref()?);
+        Some(
+            last_gossiped
+                .iter()
+                .filter(|element| current.binary_search(element).is_err())
+                .copied()
+                .collect(),
+        )
+    }
+
+    fn add_message(&self, message: MessageContent) {
+        self.merge([message]);
     }
 
-    fn add_message(&self, message: MessageContent) -> Result<()> {
-        let mut messages = self
-            .messages
-            .lock()
-            .map_err(|e| anyhow!("Failed to lock messages: {}", e))?;
-        messages.insert(message);
-        self.log(format!("Node {}: Added message: {}", self.node_id, message));
-        Ok(())
+    /// Every element, sorted so replies are deterministic.
+    fn get_all_messages(&self) -> Vec<MessageContent> {
+        lock(&self.messages).iter().copied().collect()
     }
 
-    fn get_all_messages(&self) -> Result<Vec<MsgId>> {
-        if let Ok(message_lock) = self.messages.lock() {
-            Ok(message_lock.iter().cloned().collect::<Vec<MsgId>>())
-        } else {
-            bail!("Could not acquire lock on messages")
+    /// Up to `size` elements from `cursor` on, in ascending order, and the
+    /// element the page after starts at, if any.
+    fn page(
+        &self,
+        cursor: MessageContent,
+        size: usize,
+    ) -> (Vec<MessageContent>, Option<MessageContent>) {
+        let messages = lock(&self.messages);
+        let mut rest = messages.range(cursor..).copied();
+        let page = rest.by_ref().take(size).collect();
+        (page, rest.next())
+    }
+
+    /// Unions another replica's elements into ours. Set union is
+    /// commutative, associative and idempotent, so replicas converge no
+    /// matter how often or in which order they merge.
+    fn merge(&self, values: impl IntoIterator<Item = MessageContent>) {
+        lock(&self.messages).extend(values);
+    }
+}
+
+/// What a g-set node needs to come back after a crash.
+#[derive(Serialize, Deserialize)]
+struct NodeState {
+    elements: Vec<MessageContent>,
+}
+
+impl Checkpoint for State {
+    type NodeState = NodeState;
+
+    fn save(&self) -> NodeState {
+        NodeState {
+            elements: self.get_all_messages(),
         }
     }
 
-    fn log(&self, text: String) {
-        let mut stderr = self
-            .stderr
-            .lock()
-            .expect("Node failed to acquire lock on stderr");
-        writeln!(stderr, "Node {}: {}", self.node_id, text).expect("Failed to log");
+    fn load(&self, saved: NodeState) {
+        *lock(&self.messages) = saved.elements.into_iter().collect();
     }
+}
 
-    fn next_message_id(&self) -> MsgId {
-        self.next_message_id.fetch_add(1 as u64, Ordering::SeqCst)
+fn handle_add(node: &Arc<Node>, message: &Message<MessageBody>) -> Result<()> {
+    let MessageBody::Add { element, .. } = message.body else {
+        bail!("handle_add called on different message");
+    };
+    node.state.add_message(element);
+    node.log(&format!(
+        "Node {}: Added message: {}",
+        node.node_id, element
+    ));
+    node.ack(message).map_err(|e| anyhow!(e))
+}
+
+/// Answers a read with our set. Adds are applied before they are acked, so
+/// a client reading from the node it added to always sees its own adds.
+/// A read with `sync` set first pulls every peer's set, which extends that
+/// to adds acked by any other node, as long as it answers within
+/// SYNC_RETRY's timeout. The read is answered once every peer answered or
+/// timed out, without whatever the silent ones have.
+fn handle_read(node: &Arc<Node>, message: &Message<MessageBody>) -> Result<()> {
+    let MessageBody::Read { msg_id, sync } = message.body else {
+        bail!("handle_read called on different message");
+    };
+    let peers: Vec<NodeId> = node.peers().cloned().collect();
+    if !sync || peers.is_empty() {
+        return reply_read(node, &message.src, msg_id);
     }
+    let pending = Arc::new(Mutex::new(SyncedRead {
+        client: message.src.clone(),
+        in_reply_to: msg_id,
+        waiting: peers.len(),
+    }));
+    let requests = peers
+        .into_iter()
+        .map(|peer| {
+            let answered = Arc::clone(&pending);
+            let missed = Arc::clone(&pending);
+            let body = MessageBody::SyncRequest {
+                msg_id: node.get_next_msg_id(),
+            };
+            let on_reply: Callback<State, MessageBody> = Box::new(move |node, response| {
+                if let Ok(response) = response
+                    && let MessageBody::SyncResponse {
+                        values, checksum, ..
+                    } = &response.body
+                {
+                    merge_checked(node, &response.src, values, *checksum);
+                }
+                peer_pulled(node, &answered).map_err(Into::into)
+            });
+            let on_timeout: TimeoutFn<State, MessageBody> = Box::new(move |node| {
+                if let Err(e) = peer_pulled(node, &missed) {
+                    node.log_error(&format!("Failed to answer synced read: {}", e));
+                }
+            });
+            (peer, body, on_reply, on_timeout)
+        })
+        .collect();
+    node.rpc_all_with_timeout(requests, SYNC_RETRY)
+        .map_err(|e| anyhow!(e))
 }
 
-fn init_node_from_stdin() -> Result<Node> {
-    // This does not work in threaded execution.
-    // Launch threads only after node initalization
-    let mut buffer = String::new();
-    let _ = std::io::stdin()
-        .read_line(&mut buffer)
-        .expect("Failed to read stdin");
-    let message: Message = serde_json::from_str(buffer.as_str())?;
-    if let MessageBody::Init { msg_id: _, node_id } = message.body {
-        Ok(Node::new(node_id))
+/// A read with `sync` set, waiting for its peers' sets.
+struct SyncedRead {
+    client: NodeId,
+    in_reply_to: MsgId,
+    // Peers that neither answered nor timed out yet
+    waiting: usize,
+}
+
+/// Counts one more peer of `pending` as done, and answers the read once it
+/// was the last. Flushes, as the last peer may have timed out on the
+/// sweeper thread, which doesn't.
+fn peer_pulled(node: &Arc<Node>, pending: &Mutex<SyncedRead>) -> Result<()> {
+    let (client, in_reply_to) = {
+        let mut pending = lock(pending);
+        pending.waiting -= 1;
+        if pending.waiting > 0 {
+            return Ok(());
+        }
+        (pending.client.clone(), pending.in_reply_to)
+    };
+    reply_read(node, &client, in_reply_to)?;
+    node.flush().map_err(|e| anyhow!(e))
+}
+
+fn reply_read(node: &Arc<Node>, client: &NodeId, in_reply_to: MsgId) -> Result<()> {
+    let all_messages = node.state.get_all_messages();
+    match node.state.missing_since_gossip(&all_messages) {
+        Some(missing) if !missing.is_empty() => {
+            node.log_error(&format!("Read is missing gossiped elements {:?}", missing))
+        }
+        Some(_) => node.log("Read is a superset of the last gossip"),
+        None => {}
+    }
+    node.send(
+        client,
+        MessageBody::ReadOk {
+            value: all_messages,
+            in_reply_to,
+            msg_id: node.get_next_msg_id(),
+        },
+    )
+    .map_err(|e| anyhow!(e))
+}
+
+fn handle_read_page(node: &Arc<Node>, message: &Message<MessageBody>) -> Result<()> {
+    let MessageBody::ReadPage { cursor, .. } = message.body else {
+        bail!("handle_read_page called on different message");
+    };
+    let (values, next_cursor) = node.state.page(cursor, PAGE_SIZE);
+    node.reply(message, |in_reply_to| MessageBody::ReadPageOk {
+        in_reply_to,
+        values,
+        next_cursor,
+    })
+    .map_err(|e| anyhow!(e))
+}
+
+/// A checksum of a set given in ascending order. DefaultHasher isn't stable
+/// across Rust releases, which is fine as long as the whole cluster runs
+/// one binary.
+fn checksum(sorted: &[MessageContent]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    sorted.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Why a payload of `values` claiming `claimed` doesn't add up, given our
+/// set after merging it. When both sets have the same size they should be
+/// identical, so their checksums are compared too.
+fn checksum_mismatch(
+    values: &[MessageContent],
+    claimed: u64,
+    merged: &[MessageContent],
+) -> Option<String> {
+    if checksum(values) != claimed {
+        Some("values don't match their checksum".to_string())
+    } else if merged.len() == values.len() && checksum(merged) != claimed {
+        Some("set differs from the sender's despite equal size".to_string())
     } else {
-        return Err(anyhow!("Message received was not Init"));
+        None
     }
 }
 
-fn main() -> Result<()> {
-    let node = init_node_from_stdin()?;
-    loop {
-        match node.receive() {
-            Ok(message) => match message.body {
-                MessageBody::Add { msg_id, element } => {
-                    let _ = node.add_message(element);
-                    let response_body = MessageBody::AddOk {
-                        in_reply_to: msg_id,
-                    };
-                    let _ = node.send(&message.src, response_body);
+/// Merges `values` from `peer` and, if it sent a checksum, logs any
+/// mismatch.
+fn merge_checked(node: &Arc<Node>, peer: &NodeId, values: &[MessageContent], claimed: Option<u64>) {
+    node.state.merge(values.iter().copied());
+    let Some(claimed) = claimed else {
+        return;
+    };
+    if let Some(mismatch) = checksum_mismatch(values, claimed, &node.state.get_all_messages()) {
+        node.log_error(&format!("Checksum mismatch with {}: {}", peer, mismatch));
+    }
+}
+
+fn handle_gossip(node: &Arc<Node>, message: &Message<MessageBody>) -> Result<()> {
+    let MessageBody::Gossip {
+        values, checksum, ..
+    } = &message.body
+    else {
+        bail!("handle_gossip called on different message");
+    };
+    merge_checked(node, &message.src, values, *checksum);
+    if node.state.heard_from(&message.src, Instant::now()) {
+        node.log(&format!("{} is back, pulling its set", message.src));
+        pull(node, &message.src);
+    }
+    Ok(())
+}
+
+fn handle_sync(node: &Arc<Node>, message: &Message<MessageBody>) -> Result<()> {
+    let MessageBody::SyncRequest { .. } = message.body else {
+        bail!("handle_sync called on different message");
+    };
+    let values = node.state.get_all_messages();
+    let checksum = node.state.checksum_for(&values);
+    node.reply(message, |in_reply_to| MessageBody::SyncResponse {
+        in_reply_to,
+        values,
+        checksum,
+    })
+    .map_err(|e| anyhow!(e))
+}
+
+/// Asks `peer` for its full set and merges it in.
+fn pull(node: &Arc<Node>, peer: &NodeId) {
+    let sent = node.rpc_with_timeout(
+        peer,
+        |msg_id| MessageBody::SyncRequest { msg_id },
+        SYNC_RETRY,
+        Box::new(|node, response| {
+            if let Ok(response) = response
+                && let MessageBody::SyncResponse {
+                    values, checksum, ..
+                } = &response.body
+            {
+                merge_checked(node, &response.src, values, *checksum);
+            }
+            Ok(())
+        }),
+        // The next gossip round carries the set anyway
+        Box::new(|_| {}),
+    );
+    if let Err(e) = sent {
+        node.log_error(&format!("Failed to pull from {}: {}", peer, e));
+    }
+}
+
+/// Sends our whole set to every other node, or a digest of it if digests
+/// are on.
+fn gossip(node: &Arc<Node>) {
+    if node.state.digests {
+        return gossip_digest(node);
+    }
+    let values = node.state.get_all_messages();
+    if values.is_empty() {
+        return;
+    }
+    node.state.record_gossiped(&values);
+    let checksum = node.state.checksum_for(&values);
+    node.broadcast_to_peers(|msg_id| MessageBody::Gossip {
+        msg_id,
+        values: values.clone(),
+        checksum,
+    });
+    if let Err(e) = node.flush() {
+        node.log_error(&format!("Failed to flush gossip: {}", e));
+    }
+}
+
+/// Sends every other node a Bloom filter of our set and merges the
+/// elements it answers with. A set that barely changed between rounds then
+/// costs about 10 bits per element instead of the element itself. Each
+/// round hashes with a fresh seed, so an element the peer lacks but one
+/// round's filter wrongly claims still reaches it in a later round.
+///
+/// Unlike full gossip this pulls, so it goes out even while our set is
+/// empty.
+fn gossip_digest(node: &Arc<Node>) {
+    let peers: Vec<NodeId> = node.peers().cloned().collect();
+    if peers.is_empty() {
+        return;
+    }
+    let values = node.state.get_all_messages();
+    node.state.record_gossiped(&values);
+    let seed = node.get_next_msg_id();
+    let filter_bytes = BloomFilter::of(&values, seed).encode();
+    let requests = peers
+        .into_iter()
+        .map(|peer| {
+            let body = MessageBody::Digest {
+                msg_id: node.get_next_msg_id(),
+                filter_bytes: filter_bytes.clone(),
+                seed,
+            };
+            let on_reply: Callback<State, MessageBody> = Box::new(|node, response| {
+                if let Ok(response) = response
+                    && let MessageBody::DigestOk { values, .. } = &response.body
+                {
+                    node.state.merge(values.iter().copied());
+                }
+                Ok(())
+            });
+            // The next round asks again
+            let on_timeout: TimeoutFn<State, MessageBody> = Box::new(|_| {});
+            (peer, body, on_reply, on_timeout)
+        })
+        .collect();
+    if let Err(e) = node.rpc_all_with_timeout(requests, SYNC_RETRY) {
+        node.log_error(&format!("Failed to send digests: {}", e));
+    }
+    if let Err(e) = node.flush() {
+        node.log_error(&format!("Failed to flush digests: {}", e));
+    }
+}
+
+/// Answers a peer's digest with the elements its filter doesn't contain.
+fn handle_digest(node: &Arc<Node>, message: &Message<MessageBody>) -> Result<()> {
+    let MessageBody::Digest {
+        filter_bytes, seed, ..
+    } = &message.body
+    else {
+        bail!("handle_digest called on different message");
+    };
+    let Some(filter) = BloomFilter::decode(filter_bytes, *seed) else {
+        bail!("Invalid digest from {}", message.src);
+    };
+    if node.state.heard_from(&message.src, Instant::now()) {
+        node.log(&format!("{} is back, pulling its set", message.src));
+        pull(node, &message.src);
+    }
+    let values = node
+        .state
+        .get_all_messages()
+        .into_iter()
+        .filter(|element| !filter.contains(*element))
+        .collect();
+    node.reply(message, |in_reply_to| MessageBody::DigestOk {
+        in_reply_to,
+        values,
+    })
+    .map_err(|e| anyhow!(e))
+}
+
+/// The gossip interval from MAELSTROM_GOSSIP_MS (`configured`), which has
+/// to be a positive number of milliseconds.
+fn gossip_interval(configured: Option<String>) -> Result<Duration> {
+    let Some(configured) = configured else {
+        return Ok(GOSSIP_INTERVAL);
+    };
+    match configured.parse() {
+        Ok(0) | Err(_) => bail!(
+            "MAELSTROM_GOSSIP_MS must be a positive number of milliseconds, got '{}'",
+            configured
+        ),
+        Ok(ms) => Ok(Duration::from_millis(ms)),
+    }
+}
+
+/// Picks the worker for `message` by sender, so each client's requests are
+/// handled in the order they arrived.
+fn shard(message: &Message<MessageBody>, num_workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    message.src.hash(&mut hasher);
+    (hasher.finish() % num_workers as u64) as usize
+}
+
+/// Reads messages on a dedicated thread and hands them to a pool of workers
+/// until stdin closes, while periodic tasks started with `every` run on
+/// threads of their own. Returns once the workers drained their queues.
+/// Every handler only touches state behind its mutex, so an add
+/// acknowledged by one worker is seen by any later read on another.
+fn serve(node: &Arc<Node>) {
+    let (senders, receivers): (Vec<_>, Vec<_>) = (0..WORKERS)
+        .map(|_| bounded::<Message<MessageBody>>(QUEUE_SIZE))
+        .unzip();
+    let workers: Vec<_> = receivers
+        .into_iter()
+        .map(|worker_rx| {
+            let node = Arc::clone(node);
+            thread::spawn(move || {
+                for message in worker_rx.iter() {
+                    node.dispatch(&message);
+                    // Flush once the queue is drained rather than after every send
+                    if worker_rx.is_empty()
+                        && let Err(e) = node.flush()
+                    {
+                        node.log_error(&format!("Failed to flush stdout: {}", e));
+                    }
                 }
-                MessageBody::Read { msg_id } => {
-                    let all_messages = node.get_all_messages()?;
-                    let response_body = MessageBody::ReadOk {
-                        value: all_messages,
-                        in_reply_to: msg_id,
-                        msg_id: node.next_message_id(),
-                    };
-                    let _ = node.send(&message.src, response_body);
+            })
+        })
+        .collect();
+    let reader_node = Arc::clone(node);
+    // The queues close when this thread ends, which lets the workers drain
+    // them and exit
+    let reader = thread::spawn(move || {
+        loop {
+            let message = match reader_node.receive() {
+                Ok(message) => message,
+                Err(ReceiveError::Eof) => break,
+                Err(e) => {
+                    reader_node.log_warn(&format!("Error reading message: {}", e));
+                    continue;
                 }
-                _ => {
-                    node.log(format!("Unkown message body: {:?}", message));
+            };
+            if senders[shard(&message, senders.len())]
+                .send(message)
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    let _ = reader.join();
+    for worker in workers {
+        let _ = worker.join();
+    }
+}
+
+fn main() -> Result<()> {
+    let interval = gossip_interval(std::env::var("MAELSTROM_GOSSIP_MS").ok())?;
+    let mut state = if std::env::var(CHECK_READS_ENV).is_ok_and(|value| value == "1") {
+        State::checking_reads()
+    } else {
+        State::default()
+    };
+    state.checksums = std::env::var(CHECKSUMS_ENV).is_ok_and(|value| value == "1");
+    state.digests = std::env::var(DIGESTS_ENV).is_ok_and(|value| value == "1");
+    let node = Node::init(state).map_err(|e| anyhow!(e))?;
+    if node.state.last_gossiped.is_some() {
+        node.log("Checking every read against the last gossip");
+    }
+    if node.state.checksums {
+        node.log("Checksumming gossip and sync");
+    }
+    if node.state.digests {
+        node.log("Gossiping digests instead of full sets");
+    }
+    node.register("add", handle_add);
+    node.register("read", handle_read);
+    node.register("read_page", handle_read_page);
+    node.register("gossip", handle_gossip);
+    node.register("sync", handle_sync);
+    node.register("digest", handle_digest);
+    // Init was read above, before any of these threads exist
+    node.log(&format!("Gossiping every {:?}", interval));
+    node.every(interval, Box::new(gossip));
+    serve(&node);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom_node::testing::Network;
+    use proptest::prelude::*;
+    use std::collections::HashSet;
+    use std::io::{BufRead, BufReader, Write};
+
+    fn replica(id: &str, adds: &[MessageContent]) -> Arc<Node> {
+        let node = Node::new(&NodeId::from(id), vec![], State::default());
+        for &element in adds {
+            node.state.add_message(element);
+        }
+        node
+    }
+
+    fn contents(node: &Node) -> HashSet<MessageContent> {
+        node.state.get_all_messages().into_iter().collect()
+    }
+
+    #[test]
+    fn elements_are_read_in_sorted_order() {
+        let node = replica("n1", &[30, 1, 20, 1]);
+        assert_eq!(node.state.get_all_messages(), [1, 20, 30]);
+    }
+
+    #[test]
+    fn pages_end_exactly_at_the_last_element() {
+        assert_eq!(State::default().page(0, 2), (vec![], None));
+        let node = replica("n1", &[4, 1, 3, 2]);
+        assert_eq!(node.state.page(0, 2), (vec![1, 2], Some(3)));
+        assert_eq!(node.state.page(3, 2), (vec![3, 4], None));
+        assert_eq!(node.state.page(5, 2), (vec![], None));
+        // An element added before the cursor doesn't shift the next page
+        node.state.add_message(0);
+        assert_eq!(node.state.page(3, 2), (vec![3, 4], None));
+    }
+
+    #[test]
+    fn checksum_mismatches_are_explained() {
+        let sent = [1, 2, 3];
+        let claimed = checksum(&sent);
+        assert_eq!(checksum_mismatch(&sent, claimed, &[1, 2, 3]), None);
+        assert_eq!(checksum_mismatch(&sent, claimed, &[0, 1, 2, 3]), None);
+        assert!(checksum_mismatch(&[1, 2, 4], claimed, &[1, 2, 4]).is_some());
+        assert!(checksum_mismatch(&sent, claimed, &[1, 2, 5]).is_some());
+    }
+
+    #[test]
+    fn reads_are_checked_against_the_last_gossip() {
+        assert_eq!(State::default().missing_since_gossip(&[1]), None);
+        let state = State::checking_reads();
+        state.record_gossiped(&[1, 2, 3]);
+        assert_eq!(state.missing_since_gossip(&[1, 2, 3, 4]), Some(vec![]));
+        assert_eq!(state.missing_since_gossip(&[1, 3]), Some(vec![2]));
+    }
+
+    #[test]
+    fn gossip_interval_must_be_positive() {
+        assert_eq!(gossip_interval(None).unwrap(), GOSSIP_INTERVAL);
+        assert_eq!(
+            gossip_interval(Some("50".to_string())).unwrap(),
+            Duration::from_millis(50)
+        );
+        assert!(gossip_interval(Some("0".to_string())).is_err());
+        assert!(gossip_interval(Some("fast".to_string())).is_err());
+    }
+
+    #[test]
+    fn replicas_converge_after_a_partition_heals() {
+        let mut network = Network::new(3, State::default, |node: &Arc<Node>| {
+            node.register("add", handle_add);
+            node.register("gossip", handle_gossip);
+        });
+        network.partition(&["n0"], &["n1", "n2"]);
+        network.send(r#"{"src":"c1","dest":"n0","body":{"type":"add","msg_id":1,"element":1}}"#);
+        network.send(r#"{"src":"c2","dest":"n2","body":{"type":"add","msg_id":1,"element":2}}"#);
+        network.deliver_all();
+        network.tick(gossip);
+        network.deliver_all();
+        assert!(network.dropped() > 0);
+        assert_eq!(network.nodes()[0].state.get_all_messages(), [1]);
+        assert_eq!(network.nodes()[1].state.get_all_messages(), [2]);
+
+        network.heal();
+        network.tick(gossip);
+        network.deliver_all();
+        for node in network.nodes() {
+            assert_eq!(node.state.get_all_messages(), [1, 2]);
+        }
+    }
+
+    fn reading_network() -> Network<State, MessageBody> {
+        Network::new(3, State::default, |node: &Arc<Node>| {
+            node.register("add", handle_add);
+            node.register("read", handle_read);
+            node.register("sync", handle_sync);
+        })
+    }
+
+    /// The elements in the read_ok among `outbox`.
+    fn read_value(outbox: &[String]) -> Vec<MessageContent> {
+        outbox
+            .iter()
+            .map(|line| serde_json::from_str::<Message<MessageBody>>(line).unwrap())
+            .find_map(|message| match message.body {
+                MessageBody::ReadOk { value, .. } => Some(value),
+                _ => None,
+            })
+            .expect("a read_ok")
+    }
+
+    #[test]
+    fn reads_see_adds_acked_by_the_same_node() {
+        let mut network = reading_network();
+        network.send(r#"{"src":"c1","dest":"n0","body":{"type":"add","msg_id":1,"element":7}}"#);
+        network.send(r#"{"src":"c1","dest":"n0","body":{"type":"read","msg_id":2}}"#);
+        network.deliver_all();
+        assert_eq!(read_value(&network.take_outbox()), [7]);
+    }
+
+    #[test]
+    fn synced_reads_see_adds_acked_by_another_node() {
+        let mut network = reading_network();
+        network.send(r#"{"src":"c1","dest":"n0","body":{"type":"add","msg_id":1,"element":7}}"#);
+        network.deliver_all();
+
+        // Before any gossip, a plain read elsewhere misses the add
+        network.send(r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":2}}"#);
+        network.deliver_all();
+        assert!(read_value(&network.take_outbox()).is_empty());
+
+        network.send(r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":3,"sync":true}}"#);
+        network.deliver_all();
+        assert_eq!(read_value(&network.take_outbox()), [7]);
+    }
+
+    #[test]
+    fn synced_reads_answer_without_peers_that_time_out() {
+        let mut network = reading_network();
+        network.send(r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":1,"element":7}}"#);
+        network.deliver_all();
+        network.partition(&["n0"], &["n1", "n2"]);
+        network.send(r#"{"src":"c1","dest":"n0","body":{"type":"read","msg_id":2,"sync":true}}"#);
+        network.deliver_all();
+        assert!(
+            network
+                .take_outbox()
+                .iter()
+                .all(|line| !line.contains("read_ok"))
+        );
+
+        thread::sleep(SYNC_RETRY.timeout);
+        network.tick(|_| {});
+        network.deliver_all();
+        assert!(read_value(&network.take_outbox()).is_empty());
+    }
+
+    #[test]
+    fn gossip_runs_while_the_reader_waits_for_input() {
+        let (input, mut client) = std::io::pipe().unwrap();
+        let (output, output_writer) = std::io::pipe().unwrap();
+        let node = Node::with_io(
+            &NodeId::from("n0"),
+            vec![NodeId::from("n0"), NodeId::from("n1")],
+            State::default(),
+            Box::new(BufReader::new(input)),
+            Box::new(output_writer),
+        );
+        node.register("add", handle_add);
+        node.every(Duration::from_millis(10), Box::new(gossip));
+        let server = {
+            let node = Arc::clone(&node);
+            thread::spawn(move || serve(&node))
+        };
+
+        writeln!(
+            client,
+            r#"{{"src":"c1","dest":"n0","body":{{"type":"add","msg_id":1,"element":7}}}}"#
+        )
+        .unwrap();
+        // The reader now blocks on the open pipe, and gossip still goes out
+        let gossiped = BufReader::new(output)
+            .lines()
+            .map(|line| serde_json::from_str::<Message<MessageBody>>(&line.unwrap()).unwrap())
+            .find_map(|message| match message.body {
+                MessageBody::Gossip { values, .. } => Some(values),
+                _ => None,
+            });
+        assert_eq!(gossiped, Some(vec![7]));
+
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn worker_pool_serves_interleaved_adds_and_reads() {
+        const CLIENTS: u64 = 8;
+        const ADDS: u64 = 50;
+        let (input, mut client) = std::io::pipe().unwrap();
+        let (output, output_writer) = std::io::pipe().unwrap();
+        let node = Node::with_io(
+            &NodeId::from("n0"),
+            vec![NodeId::from("n0")],
+            State::default(),
+            Box::new(BufReader::new(input)),
+            Box::new(output_writer),
+        );
+        node.register("add", handle_add);
+        node.register("read", handle_read);
+        let server = {
+            let node = Arc::clone(&node);
+            thread::spawn(move || serve(&node))
+        };
+
+        // Clients are spread over the workers, each adding its own elements
+        // with a read after every few adds
+        for i in 0..ADDS {
+            for c in 0..CLIENTS {
+                let element = c * ADDS + i;
+                writeln!(
+                    client,
+                    r#"{{"src":"c{}","dest":"n0","body":{{"type":"add","msg_id":{},"element":{}}}}}"#,
+                    c,
+                    2 * i,
+                    element
+                )
+                .unwrap();
+                if i % 5 == 4 {
+                    writeln!(
+                        client,
+                        r#"{{"src":"c{}","dest":"n0","body":{{"type":"read","msg_id":{}}}}}"#,
+                        c,
+                        2 * i + 1
+                    )
+                    .unwrap();
                 }
-            },
-            Err(e) => {
-                node.log(format!("Failed to receive message: {}", e));
             }
         }
+        let mut replies = BufReader::new(output)
+            .lines()
+            .map(|line| serde_json::from_str::<Message<MessageBody>>(&line.unwrap()).unwrap());
+        let mut acked = 0;
+        while acked < CLIENTS * ADDS {
+            match replies.next().unwrap().body {
+                MessageBody::AddOk { .. } => acked += 1,
+                // A client's reads come after its own adds on the same worker
+                MessageBody::ReadOk {
+                    in_reply_to, value, ..
+                } => {
+                    assert!(value.len() as u64 > in_reply_to / 2, "{:?}", value);
+                }
+                _ => {}
+            }
+        }
+        // Every add was acked, so a read on any worker has to see them all
+        writeln!(
+            client,
+            r#"{{"src":"c0","dest":"n0","body":{{"type":"read","msg_id":1000}}}}"#
+        )
+        .unwrap();
+        let last_read = replies.find_map(|reply| match reply.body {
+            MessageBody::ReadOk {
+                in_reply_to: 1000,
+                value,
+                ..
+            } => Some(value),
+            _ => None,
+        });
+        assert_eq!(last_read, Some((0..CLIENTS * ADDS).collect()));
+
+        drop(client);
+        server.join().unwrap();
+    }
+
+    /// Bytes a round of gossip costs three replicas that share 1000
+    /// elements and differ by a few, and whether they converge after it.
+    fn gossip_round_bytes(digests: bool) -> (u64, bool) {
+        let state = || State {
+            digests,
+            ..State::default()
+        };
+        let mut network = Network::new(3, state, |node: &Arc<Node>| {
+            node.register("gossip", handle_gossip);
+            node.register("digest", handle_digest);
+        });
+        let shared: Vec<MessageContent> = (0..1000).map(|i| 1_000_000 + i * 7919).collect();
+        for (i, node) in network.nodes().iter().enumerate() {
+            node.state.merge(shared.iter().copied());
+            node.state.add_message(i as MessageContent);
+        }
+        network.tick(gossip);
+        network.deliver_all();
+        let bytes = network
+            .nodes()
+            .iter()
+            .flat_map(|node| node.bytes_sent_by_type().into_values())
+            .sum();
+        let converged = network
+            .nodes()
+            .iter()
+            .all(|node| node.state.get_all_messages().len() == shared.len() + 3);
+        (bytes, converged)
+    }
+
+    #[test]
+    fn digests_cost_fewer_bytes_than_full_sets() {
+        let (full, full_converged) = gossip_round_bytes(false);
+        let (digest, digest_converged) = gossip_round_bytes(true);
+        assert!(full_converged && digest_converged);
+        assert!(
+            digest * 3 < full,
+            "digests took {} bytes, full sets {}",
+            digest,
+            full
+        );
+    }
+
+    #[test]
+    fn digests_are_answered_with_what_the_sender_lacks() {
+        let mut network = Network::new(2, State::default, |node: &Arc<Node>| {
+            node.register("digest", handle_digest);
+        });
+        network.nodes()[0].state.merge([1, 2]);
+        network.nodes()[1].state.merge([2, 3, 4]);
+        gossip_digest(&network.nodes()[0]);
+        network.deliver_all();
+        assert_eq!(network.nodes()[0].state.get_all_messages(), [1, 2, 3, 4]);
+        // Digests only pull; n1 learns of 1 from its own
+        assert_eq!(network.nodes()[1].state.get_all_messages(), [2, 3, 4]);
+
+        network.send(
+            r#"{"src":"n0","dest":"n1","body":{"type":"digest","msg_id":9,"filter_bytes":"","seed":1}}"#,
+        );
+        network.deliver_all();
+        assert!(network.take_outbox().is_empty());
+    }
+
+    #[test]
+    fn peers_back_from_silence_are_pulled_from() {
+        let state = State::default();
+        let n2 = NodeId::from("n2");
+        let start = Instant::now();
+        assert!(!state.heard_from(&n2, start));
+        assert!(!state.heard_from(&n2, start + GOSSIP_INTERVAL));
+        assert!(state.heard_from(&n2, start + GOSSIP_INTERVAL + REJOIN_AFTER * 2));
+
+        let mut network = Network::new(2, State::default, |node: &Arc<Node>| {
+            node.register("sync", handle_sync);
+        });
+        network.nodes()[1].state.merge([1, 2]);
+        pull(&network.nodes()[0], &NodeId::from("n1"));
+        network.nodes()[0].flush().unwrap();
+        network.deliver_all();
+        assert_eq!(network.nodes()[0].state.get_all_messages(), [1, 2]);
+    }
+
+    proptest! {
+        #[test]
+        fn replicas_converge_to_the_union(
+            adds in prop::collection::vec((any::<bool>(), 0..50u64), 0..40)
+        ) {
+            let (left, right): (Vec<_>, Vec<_>) = adds.iter().partition(|(on_n1, _)| *on_n1);
+            let n1 = replica("n1", &left.iter().map(|(_, e)| *e).collect::<Vec<_>>());
+            let n2 = replica("n2", &right.iter().map(|(_, e)| *e).collect::<Vec<_>>());
+            let n1_before = n1.state.get_all_messages();
+
+            n1.state.merge(n2.state.get_all_messages());
+            n2.state.merge(n1_before);
+
+            let union: HashSet<_> = adds.iter().map(|(_, e)| *e).collect();
+            prop_assert_eq!(contents(&n1), union.clone());
+            prop_assert_eq!(contents(&n2), union);
+        }
+
+        #[test]
+        fn merge_is_commutative_and_idempotent(
+            a in prop::collection::vec(0..50u64, 0..20),
+            b in prop::collection::vec(0..50u64, 0..20),
+        ) {
+            let ab = replica("n1", &a);
+            ab.state.merge(b.iter().copied());
+            let ba = replica("n2", &b);
+            ba.state.merge(a.iter().copied());
+            prop_assert_eq!(contents(&ab), contents(&ba));
+
+            let merged = contents(&ab);
+            ab.state.merge(b.iter().copied());
+            ab.state.merge(merged.iter().copied());
+            prop_assert_eq!(contents(&ab), merged);
+        }
     }
 }
@@ -0,0 +1,33 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const INIT: &str = r#"{"src":"c0","dest":"n0","body":{"type":"init","msg_id":1,"node_id":"n0","node_ids":["n0"]}}"#;
+
+#[test]
+fn exits_on_empty_stream_after_init() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_g-set"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start g-set");
+    {
+        let mut stdin = child.stdin.take().unwrap();
+        writeln!(stdin, "{}", INIT).unwrap();
+        writeln!(stdin, "not a message").unwrap();
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait().unwrap() {
+            assert!(status.success(), "g-set exited with {}", status);
+            return;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+    panic!("g-set kept running after stdin was closed");
+}
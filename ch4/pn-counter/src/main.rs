@@ -0,0 +1,279 @@
+use anyhow::{Result, anyhow, bail};
+use maelstrom_node::kv::{KvMessage, SEQ_KV};
+use maelstrom_node::{
+    Body, ErrorCode, KvBody, MaelstromError, Message, MsgId, NodeId, RetryPolicy, lock,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+type Node = maelstrom_node::Node<(), MessageBody>;
+// Stored counts never go negative, but the client's read_ok can
+type Kv = KvBody<String, i64>;
+
+const KV_READ_RETRY: RetryPolicy = RetryPolicy {
+    timeout: Duration::from_millis(500),
+    max_retries: 2,
+};
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+enum MessageBody {
+    #[serde(rename = "add")]
+    Add { msg_id: MsgId, delta: i64 },
+    #[serde(rename = "add_ok")]
+    AddOk { in_reply_to: MsgId },
+    #[serde(rename = "read")]
+    Read { msg_id: MsgId },
+    // Replies from seq-kv, and the client read_ok which has the same shape
+    #[serde(untagged)]
+    Kv(Kv),
+}
+
+impl Body for MessageBody {
+    fn type_tag(&self) -> &'static str {
+        match self {
+            Self::Add { .. } => "add",
+            Self::AddOk { .. } => "add_ok",
+            Self::Read { .. } => "read",
+            Self::Kv(body) => body.type_tag(),
+        }
+    }
+    fn msg_id(&self) -> Option<MsgId> {
+        match self {
+            Self::Add { msg_id, .. } => Some(*msg_id),
+            Self::Read { msg_id } => Some(*msg_id),
+            Self::Kv(body) => body.msg_id(),
+            _ => None,
+        }
+    }
+    fn in_reply_to(&self) -> Option<MsgId> {
+        match self {
+            Self::AddOk { in_reply_to } => Some(*in_reply_to),
+            Self::Kv(body) => body.in_reply_to(),
+            _ => None,
+        }
+    }
+}
+
+impl From<Kv> for MessageBody {
+    fn from(body: Kv) -> Self {
+        Self::Kv(body)
+    }
+}
+
+impl KvMessage<i64> for MessageBody {
+    fn as_kv(&self) -> Option<&Kv> {
+        match self {
+            Self::Kv(body) => Some(body),
+            _ => None,
+        }
+    }
+}
+
+/// Each node owns two grow-only counts in seq-kv: increments under `<id>-p`
+/// and decrements under `<id>-n`. The counter's value is the sum of every P
+/// minus the sum of every N.
+fn count_key(node_id: &NodeId, positive: bool) -> String {
+    format!("{}-{}", node_id, if positive { "p" } else { "n" })
+}
+
+fn handle_add(node: &Arc<Node>, message: &Message<MessageBody>) -> Result<()> {
+    let MessageBody::Add { delta, .. } = message.body else {
+        bail!("handle_add called on different message");
+    };
+    // i64::MIN has no positive counterpart to add to the N count
+    let Some(amount) = delta.checked_abs() else {
+        return node
+            .reply_error(
+                message,
+                ErrorCode::MalformedRequest,
+                &format!("Can't add {}", delta),
+            )
+            .map_err(|e| anyhow!(e));
+    };
+    let request = message.clone();
+    node.kv_update(
+        SEQ_KV,
+        &count_key(&node.node_id, delta >= 0),
+        move |current| current.unwrap_or(0) + amount,
+        move |node, outcome| {
+            let replied = match outcome {
+                Ok(_) => node.reply(&request, |in_reply_to| MessageBody::AddOk { in_reply_to }),
+                Err(code) => node.reply_error(&request, code, "Failed to update count"),
+            };
+            if let Err(e) = replied.and_then(|()| node.flush()) {
+                node.log_error(&format!("Failed to answer add: {}", e));
+            }
+        },
+    );
+    Ok(())
+}
+
+/// A client read waiting for every node's P and N counts.
+struct PendingRead {
+    request: Message<MessageBody>,
+    remaining: usize,
+    sum: i64,
+    failed: bool,
+}
+
+/// Adds one count to a pending read, or marks it failed, and answers the
+/// client once every count has been accounted for.
+fn complete_read(node: &Arc<Node>, pending: &Mutex<PendingRead>, count: Option<i64>) {
+    let mut pending = lock(pending);
+    match count {
+        Some(count) => pending.sum += count,
+        None => pending.failed = true,
+    }
+    pending.remaining -= 1;
+    if pending.remaining > 0 {
+        return;
+    }
+    let replied = if pending.failed {
+        node.reply_error(
+            &pending.request,
+            ErrorCode::Timeout,
+            "Failed to read counts",
+        )
+    } else {
+        let value = pending.sum;
+        node.reply(&pending.request, |in_reply_to| {
+            MessageBody::Kv(Kv::ReadOk { in_reply_to, value })
+        })
+    };
+    if let Err(e) = replied.and_then(|()| node.flush()) {
+        node.log_error(&format!("Failed to answer read: {}", e));
+    }
+}
+
+fn handle_read(node: &Arc<Node>, message: &Message<MessageBody>) -> Result<()> {
+    let MessageBody::Read { .. } = message.body else {
+        bail!("handle_read called on different message");
+    };
+    let counts: Vec<(String, i64)> = node
+        .node_ids
+        .iter()
+        .flat_map(|id| [(count_key(id, true), 1), (count_key(id, false), -1)])
+        .collect();
+    let pending = Arc::new(Mutex::new(PendingRead {
+        request: message.clone(),
+        remaining: counts.len(),
+        sum: 0,
+        failed: false,
+    }));
+    for (key, sign) in counts {
+        let on_reply = Arc::clone(&pending);
+        let on_timeout = Arc::clone(&pending);
+        node.rpc_with_timeout(
+            &NodeId::from(SEQ_KV),
+            |msg_id| MessageBody::Kv(Kv::Read { msg_id, key }),
+            KV_READ_RETRY,
+            Box::new(move |node, response| {
+                let count = match response.map(|response| &response.body) {
+                    Ok(MessageBody::Kv(Kv::ReadOk { value, .. })) => Some(sign * value),
+                    // Nothing was ever added in this direction
+                    Err(MaelstromError {
+                        code: ErrorCode::KeyDoesNotExist,
+                        ..
+                    }) => Some(0),
+                    reply => {
+                        node.log_warn(&format!("Unexpected reply to read: {:?}", reply));
+                        None
+                    }
+                };
+                complete_read(node, &on_reply, count);
+                Ok(())
+            }),
+            Box::new(move |node| complete_read(node, &on_timeout, None)),
+        )
+        .map_err(|e| anyhow!(e))?;
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let node = Node::init(()).map_err(|e| anyhow!(e))?;
+    node.register("add", handle_add);
+    node.register("read", handle_read);
+    node.run();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom_node::testing::{init_line, run_lines};
+
+    fn run(lines: &[&str]) -> Vec<String> {
+        let init = init_line("n1", &["n1"]);
+        let lines: Vec<&str> = [init.as_str()]
+            .into_iter()
+            .chain(lines.iter().copied())
+            .collect();
+        run_lines(
+            (),
+            |node: &Arc<Node>| {
+                node.register("add", handle_add);
+                node.register("read", handle_read);
+            },
+            &lines,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn adds_go_to_the_count_of_their_sign() {
+        let output = run(&[
+            r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":7,"delta":3}}"#,
+            r#"{"src":"seq-kv","dest":"n1","body":{"type":"error","in_reply_to":2,"code":20,"text":"not found"}}"#,
+            r#"{"src":"seq-kv","dest":"n1","body":{"type":"cas_ok","in_reply_to":3}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":8,"delta":-2}}"#,
+            r#"{"src":"seq-kv","dest":"n1","body":{"type":"read_ok","in_reply_to":4,"value":5}}"#,
+            r#"{"src":"seq-kv","dest":"n1","body":{"type":"cas_ok","in_reply_to":5}}"#,
+        ]);
+
+        assert_eq!(
+            output[1..],
+            [
+                r#"{"src":"n1","dest":"seq-kv","body":{"type":"read","msg_id":2,"key":"n1-p"}}"#,
+                r#"{"src":"n1","dest":"seq-kv","body":{"type":"cas","msg_id":3,"key":"n1-p","from":0,"to":3,"create_if_not_exists":true}}"#,
+                r#"{"src":"n1","dest":"c1","body":{"type":"add_ok","in_reply_to":7}}"#,
+                r#"{"src":"n1","dest":"seq-kv","body":{"type":"read","msg_id":4,"key":"n1-n"}}"#,
+                r#"{"src":"n1","dest":"seq-kv","body":{"type":"cas","msg_id":5,"key":"n1-n","from":5,"to":7,"create_if_not_exists":false}}"#,
+                r#"{"src":"n1","dest":"c1","body":{"type":"add_ok","in_reply_to":8}}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_subtract_the_n_counts_from_the_p_counts() {
+        let output = run(&[
+            r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":7}}"#,
+            r#"{"src":"seq-kv","dest":"n1","body":{"type":"read_ok","in_reply_to":2,"value":3}}"#,
+            r#"{"src":"seq-kv","dest":"n1","body":{"type":"read_ok","in_reply_to":3,"value":7}}"#,
+        ]);
+
+        assert_eq!(
+            output[1..],
+            [
+                r#"{"src":"n1","dest":"seq-kv","body":{"type":"read","msg_id":2,"key":"n1-p"}}"#,
+                r#"{"src":"n1","dest":"seq-kv","body":{"type":"read","msg_id":3,"key":"n1-n"}}"#,
+                r#"{"src":"n1","dest":"c1","body":{"type":"read_ok","in_reply_to":7,"value":-4}}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn adds_without_a_negation_are_refused() {
+        let output = run(&[
+            r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":7,"delta":-9223372036854775808}}"#,
+        ]);
+
+        assert_eq!(
+            output[1..],
+            [
+                r#"{"src":"n1","dest":"c1","body":{"type":"error","in_reply_to":7,"code":12,"text":"Can't add -9223372036854775808"}}"#,
+            ]
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use anyhow::{Result, anyhow, bail};
+use maelstrom_node::{Body, Message, MsgId, NodeError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+type Node = maelstrom_node::Node<State, MessageBody>;
+type Key = String;
+type Offset = u64;
+type Value = u64;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+enum MessageBody {
+    #[serde(rename = "send")]
+    Send { msg_id: MsgId, key: Key, msg: Value },
+    #[serde(rename = "send_ok")]
+    SendOk { in_reply_to: MsgId, offset: Offset },
+    #[serde(rename = "poll")]
+    Poll {
+        msg_id: MsgId,
+        offsets: HashMap<Key, Offset>,
+    },
+    #[serde(rename = "poll_ok")]
+    PollOk {
+        in_reply_to: MsgId,
+        msgs: HashMap<Key, Vec<(Offset, Value)>>,
+    },
+    #[serde(rename = "commit_offsets")]
+    CommitOffsets {
+        msg_id: MsgId,
+        offsets: HashMap<Key, Offset>,
+    },
+    #[serde(rename = "commit_offsets_ok")]
+    CommitOffsetsOk { in_reply_to: MsgId },
+    #[serde(rename = "list_committed_offsets")]
+    ListCommittedOffsets { msg_id: MsgId, keys: Vec<Key> },
+    #[serde(rename = "list_committed_offsets_ok")]
+    ListCommittedOffsetsOk {
+        in_reply_to: MsgId,
+        offsets: HashMap<Key, Offset>,
+    },
+}
+
+impl Body for MessageBody {
+    fn type_tag(&self) -> &'static str {
+        match self {
+            Self::Send { .. } => "send",
+            Self::SendOk { .. } => "send_ok",
+            Self::Poll { .. } => "poll",
+            Self::PollOk { .. } => "poll_ok",
+            Self::CommitOffsets { .. } => "commit_offsets",
+            Self::CommitOffsetsOk { .. } => "commit_offsets_ok",
+            Self::ListCommittedOffsets { .. } => "list_committed_offsets",
+            Self::ListCommittedOffsetsOk { .. } => "list_committed_offsets_ok",
+        }
+    }
+    fn msg_id(&self) -> Option<MsgId> {
+        match self {
+            Self::Send { msg_id, .. } => Some(*msg_id),
+            Self::Poll { msg_id, .. } => Some(*msg_id),
+            Self::CommitOffsets { msg_id, .. } => Some(*msg_id),
+            Self::ListCommittedOffsets { msg_id, .. } => Some(*msg_id),
+            _ => None,
+        }
+    }
+    fn in_reply_to(&self) -> Option<MsgId> {
+        match self {
+            Self::SendOk { in_reply_to, .. } => Some(*in_reply_to),
+            Self::PollOk { in_reply_to, .. } => Some(*in_reply_to),
+            Self::CommitOffsetsOk { in_reply_to } => Some(*in_reply_to),
+            Self::ListCommittedOffsetsOk { in_reply_to, .. } => Some(*in_reply_to),
+            _ => None,
+        }
+    }
+}
+
+/// A single node owns every log, so offsets are simply positions in the
+/// per-key `Vec`.
+#[derive(Default)]
+struct State {
+    logs: Mutex<Logs>,
+}
+
+#[derive(Default)]
+struct Logs {
+    entries: HashMap<Key, Vec<(Offset, Value)>>,
+    committed: HashMap<Key, Offset>,
+}
+
+impl State {
+    fn logs(&self) -> Result<MutexGuard<'_, Logs>> {
+        self.logs
+            .lock()
+            .map_err(|_| NodeError::LockPoisoned("logs").into())
+    }
+}
+
+fn handle_send(node: &Arc<Node>, message: &Message<MessageBody>) -> Result<()> {
+    let MessageBody::Send { key, msg, .. } = &message.body else {
+        bail!("handle_send called on different message");
+    };
+    let offset = {
+        let mut logs = node.state.logs()?;
+        let log = logs.entries.entry(key.clone()).or_default();
+        let offset = log.len() as Offset;
+        log.push((offset, *msg));
+        offset
+    };
+    node.reply(message, |in_reply_to| MessageBody::SendOk {
+        in_reply_to,
+        offset,
+    })
+    .map_err(|e| anyhow!(e))
+}
+
+fn handle_poll(node: &Arc<Node>, message: &Message<MessageBody>) -> Result<()> {
+    let MessageBody::Poll { offsets, .. } = &message.body else {
+        bail!("handle_poll called on different message");
+    };
+    let msgs = {
+        let logs = node.state.logs()?;
+        offsets
+            .iter()
+            .filter_map(|(key, &from)| {
+                let log = logs.entries.get(key)?;
+                Some((key.clone(), log.get(from as usize..)?.to_vec()))
+            })
+            .collect()
+    };
+    node.reply(message, |in_reply_to| MessageBody::PollOk {
+        in_reply_to,
+        msgs,
+    })
+    .map_err(|e| anyhow!(e))
+}
+
+fn handle_commit_offsets(node: &Arc<Node>, message: &Message<MessageBody>) -> Result<()> {
+    let MessageBody::CommitOffsets { offsets, .. } = &message.body else {
+        bail!("handle_commit_offsets called on different message");
+    };
+    {
+        let mut logs = node.state.logs()?;
+        for (key, &offset) in offsets {
+            // Commits never move backwards, even if they arrive out of order
+            let committed = logs.committed.entry(key.clone()).or_default();
+            *committed = (*committed).max(offset);
+        }
+    }
+    node.reply(message, |in_reply_to| MessageBody::CommitOffsetsOk {
+        in_reply_to,
+    })
+    .map_err(|e| anyhow!(e))
+}
+
+fn handle_list_committed_offsets(node: &Arc<Node>, message: &Message<MessageBody>) -> Result<()> {
+    let MessageBody::ListCommittedOffsets { keys, .. } = &message.body else {
+        bail!("handle_list_committed_offsets called on different message");
+    };
+    let offsets = {
+        let logs = node.state.logs()?;
+        keys.iter()
+            .filter_map(|key| Some((key.clone(), *logs.committed.get(key)?)))
+            .collect()
+    };
+    node.reply(message, |in_reply_to| MessageBody::ListCommittedOffsetsOk {
+        in_reply_to,
+        offsets,
+    })
+    .map_err(|e| anyhow!(e))
+}
+
+fn main() -> Result<()> {
+    let node = Node::init(State::default()).map_err(|e| anyhow!(e))?;
+    node.register("send", handle_send);
+    node.register("poll", handle_poll);
+    node.register("commit_offsets", handle_commit_offsets);
+    node.register("list_committed_offsets", handle_list_committed_offsets);
+    node.run();
+    Ok(())
+}
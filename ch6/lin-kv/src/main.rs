@@ -0,0 +1,100 @@
+use anyhow::{Result, anyhow, bail};
+use maelstrom_node::kv::{self, LIN_KV};
+use maelstrom_node::{ErrorCode, KvBody, Message, NodeId, RetryPolicy};
+use std::sync::Arc;
+use std::time::Duration;
+
+type Kv = KvBody<u64, u64>;
+type Node = maelstrom_node::Node<(), Kv>;
+
+const READ_RETRY: RetryPolicy = RetryPolicy {
+    timeout: Duration::from_millis(500),
+    max_retries: 2,
+};
+// A resent cas that already applied would come back precondition-failed,
+// which the client would take as definite. Writes and cas are sent once.
+const UPDATE_RETRY: RetryPolicy = RetryPolicy {
+    timeout: Duration::from_millis(500),
+    max_retries: 0,
+};
+
+/// Forwards the client's request to lin-kv and relays its answer, keeping
+/// the error code so `key-does-not-exist` and `precondition-failed` reach
+/// the client.
+fn handle_request(node: &Arc<Node>, message: &Message<Kv>) -> Result<()> {
+    let policy = match message.body {
+        Kv::Read { .. } => READ_RETRY,
+        Kv::Write { .. } | Kv::Cas { .. } => UPDATE_RETRY,
+        _ => bail!("handle_request called on different message"),
+    };
+    let request = message.clone();
+    let timed_out = message.clone();
+    node.rpc_with_timeout(
+        &NodeId::from(LIN_KV),
+        |msg_id| message.body.with_msg_id(msg_id),
+        policy,
+        Box::new(move |node, response| {
+            match response {
+                Ok(response) => kv::relay(node, LIN_KV, &request, &response.body)?,
+                Err(e) => node.reply_error(&request, e.code, &e.text)?,
+            }
+            node.flush()
+        }),
+        Box::new(move |node| {
+            // The write may or may not have happened, which timeout conveys
+            let replied = node
+                .reply_error(&timed_out, ErrorCode::Timeout, "lin-kv did not answer")
+                .and_then(|()| node.flush());
+            if let Err(e) = replied {
+                node.log_error(&format!("Failed to answer timed out request: {}", e));
+            }
+        }),
+    )
+    .map_err(|e| anyhow!(e))?;
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let node = Node::init(()).map_err(|e| anyhow!(e))?;
+    node.register("read", handle_request);
+    node.register("write", handle_request);
+    node.register("cas", handle_request);
+    node.run();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom_node::testing::{init_line, run_lines};
+
+    #[test]
+    fn relays_lin_kv_errors_and_successes_to_the_client() {
+        let init = init_line("n1", &["n1"]);
+        let output = run_lines(
+            (),
+            |node: &Arc<Node>| {
+                node.register("read", handle_request);
+                node.register("cas", handle_request);
+            },
+            &[
+                &init,
+                r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":7,"key":1}}"#,
+                r#"{"src":"lin-kv","dest":"n1","body":{"type":"error","in_reply_to":2,"code":20,"text":"not found"}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"cas","msg_id":8,"key":1,"from":0,"to":3,"create_if_not_exists":true}}"#,
+                r#"{"src":"lin-kv","dest":"n1","body":{"type":"cas_ok","in_reply_to":3}}"#,
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            output[1..],
+            [
+                r#"{"src":"n1","dest":"lin-kv","body":{"type":"read","msg_id":2,"key":1}}"#,
+                r#"{"src":"n1","dest":"c1","body":{"type":"error","in_reply_to":7,"code":20,"text":"not found"}}"#,
+                r#"{"src":"n1","dest":"lin-kv","body":{"type":"cas","msg_id":3,"key":1,"from":0,"to":3,"create_if_not_exists":true}}"#,
+                r#"{"src":"n1","dest":"c1","body":{"type":"cas_ok","in_reply_to":8}}"#,
+            ]
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use anyhow::{Result, anyhow, bail};
+use maelstrom_node::kv::{self, LWW_KV};
+use maelstrom_node::{ErrorCode, KvBody, Message, NodeId, RetryPolicy, lock};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+type Kv = KvBody<u64, u64>;
+type Node = maelstrom_node::Node<State, Kv>;
+
+const READ_RETRY: RetryPolicy = RetryPolicy {
+    timeout: Duration::from_millis(500),
+    max_retries: 2,
+};
+// A resent write lands with a newer timestamp than anything written in the
+// meantime and would silently win over it. Writes and cas are sent once.
+const UPDATE_RETRY: RetryPolicy = RetryPolicy {
+    timeout: Duration::from_millis(500),
+    max_retries: 0,
+};
+
+/// lww-kv keeps whichever write it timestamps last, so two writes to the same
+/// key that are in flight together may land in either order. Writes and cas
+/// from this node are therefore sent one at a time per key: the next waits
+/// until lww-kv answered the previous one.
+///
+/// Reads are passed through untouched. lww-kv may answer them from a replica
+/// that hasn't seen the latest write yet, and that staleness reaches the
+/// client as is; hiding it would need a linearizable store, see lin-kv.
+#[derive(Default)]
+struct State {
+    // Writes and cas waiting per key, the one in flight at the front
+    updates: Mutex<HashMap<u64, VecDeque<Message<Kv>>>>,
+}
+
+/// Forwards `request` to lww-kv and relays the answer. `done` runs after the
+/// client was answered, whether lww-kv replied or not.
+fn forward(
+    node: &Arc<Node>,
+    request: Message<Kv>,
+    policy: RetryPolicy,
+    done: impl Fn(&Arc<Node>) + Clone + Send + 'static,
+) -> maelstrom_node::Result<()> {
+    let timed_out = request.clone();
+    let on_timeout = done.clone();
+    let body = request.body.clone();
+    node.rpc_with_timeout(
+        &NodeId::from(LWW_KV),
+        |msg_id| body.with_msg_id(msg_id),
+        policy,
+        Box::new(move |node, response| {
+            let relayed = match response {
+                Ok(response) => kv::relay(node, LWW_KV, &request, &response.body),
+                Err(e) => node.reply_error(&request, e.code, &e.text),
+            };
+            let relayed = relayed.and_then(|()| node.flush());
+            done(node);
+            relayed
+        }),
+        Box::new(move |node| {
+            let replied = node
+                .reply_error(&timed_out, ErrorCode::Timeout, "lww-kv did not answer")
+                .and_then(|()| node.flush());
+            if let Err(e) = replied {
+                node.log_error(&format!("Failed to answer timed out request: {}", e));
+            }
+            on_timeout(node);
+        }),
+    )?;
+    Ok(())
+}
+
+/// Sends the update at the front of `key`'s queue, if any. Updates that
+/// can't be sent are answered with an error and skipped.
+fn send_next_update(node: &Arc<Node>, key: u64) {
+    loop {
+        let Some(request) = lock(&node.state.updates)
+            .get(&key)
+            .and_then(|queue| queue.front().cloned())
+        else {
+            return;
+        };
+        match forward(node, request.clone(), UPDATE_RETRY, move |node| {
+            finish_update(node, key)
+        }) {
+            Ok(()) => return,
+            Err(e) => {
+                node.log_error(&format!("Failed to forward update: {}", e));
+                let _ = node.reply_error(&request, ErrorCode::Crash, "Failed to forward");
+                pop_update(node, key);
+            }
+        }
+    }
+}
+
+fn pop_update(node: &Arc<Node>, key: u64) {
+    let mut updates = lock(&node.state.updates);
+    if let Some(queue) = updates.get_mut(&key) {
+        queue.pop_front();
+        if queue.is_empty() {
+            updates.remove(&key);
+        }
+    }
+}
+
+fn finish_update(node: &Arc<Node>, key: u64) {
+    pop_update(node, key);
+    send_next_update(node, key);
+}
+
+fn handle_update(node: &Arc<Node>, message: &Message<Kv>) -> Result<()> {
+    let (Kv::Write { key, .. } | Kv::Cas { key, .. }) = message.body else {
+        bail!("handle_update called on different message");
+    };
+    let idle = {
+        let mut updates = lock(&node.state.updates);
+        let queue = updates.entry(key).or_default();
+        queue.push_back(message.clone());
+        queue.len() == 1
+    };
+    if idle {
+        send_next_update(node, key);
+    }
+    Ok(())
+}
+
+fn handle_read(node: &Arc<Node>, message: &Message<Kv>) -> Result<()> {
+    let Kv::Read { .. } = message.body else {
+        bail!("handle_read called on different message");
+    };
+    forward(node, message.clone(), READ_RETRY, |_| {}).map_err(|e| anyhow!(e))
+}
+
+fn main() -> Result<()> {
+    let node = Node::init(State::default()).map_err(|e| anyhow!(e))?;
+    node.register("read", handle_read);
+    node.register("write", handle_update);
+    node.register("cas", handle_update);
+    node.run();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom_node::testing::{init_line, run_lines};
+
+    #[test]
+    fn writes_to_one_key_wait_for_the_previous_one() {
+        let init = init_line("n1", &["n1"]);
+        let output = run_lines(
+            State::default(),
+            |node: &Arc<Node>| {
+                node.register("write", handle_update);
+            },
+            &[
+                &init,
+                r#"{"src":"c1","dest":"n1","body":{"type":"write","msg_id":7,"key":1,"value":1}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"write","msg_id":8,"key":1,"value":2}}"#,
+                r#"{"src":"lww-kv","dest":"n1","body":{"type":"write_ok","in_reply_to":2}}"#,
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            output[1..],
+            [
+                r#"{"src":"n1","dest":"lww-kv","body":{"type":"write","msg_id":2,"key":1,"value":1}}"#,
+                r#"{"src":"n1","dest":"c1","body":{"type":"write_ok","in_reply_to":7}}"#,
+                r#"{"src":"n1","dest":"lww-kv","body":{"type":"write","msg_id":3,"key":1,"value":2}}"#,
+            ]
+        );
+    }
+}
@@ -0,0 +1,371 @@
+use anyhow::{Result, anyhow, bail};
+use maelstrom_node::kv::LIN_KV;
+use maelstrom_node::{
+    Body, ErrorCode, KvBody, MaelstromError, Message, MsgId, NodeError, NodeId, RetryPolicy, TxnOp,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+type Node = maelstrom_node::Node<State, MessageBody>;
+type Registers = BTreeMap<u64, u64>;
+// In a cluster, every register lives in a single lin-kv value so one cas
+// commits a whole transaction. It is stored as `[key, value]` pairs in key
+// order: JSON object keys are strings, which don't parse back into u64
+// inside the untagged Kv variant.
+type Snapshot = Vec<(u64, u64)>;
+type Kv = KvBody<String, Snapshot>;
+
+const REGISTERS_KEY: &str = "registers";
+/// How often a transaction is retried after losing the commit cas before it
+/// is aborted with `txn-conflict`.
+const TXN_ATTEMPTS: u32 = 5;
+const KV_READ_RETRY: RetryPolicy = RetryPolicy {
+    timeout: Duration::from_millis(500),
+    max_retries: 2,
+};
+// A resent cas that already applied would fail its precondition and abort a
+// transaction that actually committed. Never resend it.
+const KV_CAS_RETRY: RetryPolicy = RetryPolicy {
+    timeout: Duration::from_millis(500),
+    max_retries: 0,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+enum MessageBody {
+    #[serde(rename = "txn")]
+    Txn { msg_id: MsgId, txn: Vec<TxnOp> },
+    #[serde(rename = "txn_ok")]
+    TxnOk { in_reply_to: MsgId, txn: Vec<TxnOp> },
+    // Replies from lin-kv
+    #[serde(untagged)]
+    Kv(Kv),
+}
+
+impl Body for MessageBody {
+    fn type_tag(&self) -> &'static str {
+        match self {
+            Self::Txn { .. } => "txn",
+            Self::TxnOk { .. } => "txn_ok",
+            Self::Kv(body) => body.type_tag(),
+        }
+    }
+    fn msg_id(&self) -> Option<MsgId> {
+        match self {
+            Self::Txn { msg_id, .. } => Some(*msg_id),
+            Self::Kv(body) => body.msg_id(),
+            _ => None,
+        }
+    }
+    fn in_reply_to(&self) -> Option<MsgId> {
+        match self {
+            Self::TxnOk { in_reply_to, .. } => Some(*in_reply_to),
+            Self::Kv(body) => body.in_reply_to(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    registers: Mutex<Registers>,
+}
+
+/// Runs `txn` against `registers` and returns it with the reads filled in.
+fn apply(registers: &mut Registers, txn: &[TxnOp]) -> Vec<TxnOp> {
+    txn.iter()
+        .map(|op| match *op {
+            TxnOp::Read { key, .. } => TxnOp::Read {
+                key,
+                value: registers.get(&key).copied(),
+            },
+            TxnOp::Write { key, value } => {
+                registers.insert(key, value);
+                *op
+            }
+        })
+        .collect()
+}
+
+fn reply_txn_ok(node: &Arc<Node>, request: &Message<MessageBody>, txn: Vec<TxnOp>) {
+    let replied = node
+        .reply(request, |in_reply_to| MessageBody::TxnOk {
+            in_reply_to,
+            txn,
+        })
+        .and_then(|()| node.flush());
+    if let Err(e) = replied {
+        node.log_error(&format!("Failed to answer txn: {}", e));
+    }
+}
+
+fn reply_txn_error(node: &Arc<Node>, request: &Message<MessageBody>, code: ErrorCode, text: &str) {
+    let replied = node
+        .reply_error(request, code, text)
+        .and_then(|()| node.flush());
+    if let Err(e) = replied {
+        node.log_error(&format!("Failed to answer txn: {}", e));
+    }
+}
+
+/// Applies the transaction to this node's own registers. Only correct when
+/// the node is alone in the cluster.
+fn handle_txn(node: &Arc<Node>, message: &Message<MessageBody>) -> Result<()> {
+    let MessageBody::Txn { txn, .. } = &message.body else {
+        bail!("handle_txn called on different message");
+    };
+    let completed = {
+        // Holding the lock for the whole transaction makes it atomic
+        let mut registers = node
+            .state
+            .registers
+            .lock()
+            .map_err(|_| NodeError::LockPoisoned("registers"))?;
+        apply(&mut registers, txn)
+    };
+    node.reply(message, |in_reply_to| MessageBody::TxnOk {
+        in_reply_to,
+        txn: completed,
+    })
+    .map_err(|e| anyhow!(e))
+}
+
+/// Runs the transaction against a snapshot of the registers read from
+/// lin-kv and commits its writes with a cas of the whole snapshot. Nothing
+/// is visible to other transactions before that cas, so reads only ever
+/// see committed writes.
+fn handle_txn_replicated(node: &Arc<Node>, message: &Message<MessageBody>) -> Result<()> {
+    let MessageBody::Txn { .. } = message.body else {
+        bail!("handle_txn_replicated called on different message");
+    };
+    run_txn(node, message.clone(), 1);
+    Ok(())
+}
+
+fn run_txn(node: &Arc<Node>, request: Message<MessageBody>, attempt: u32) {
+    let timed_out = request.clone();
+    let sent = node.rpc_with_timeout(
+        &NodeId::from(LIN_KV),
+        |msg_id| {
+            MessageBody::Kv(Kv::Read {
+                msg_id,
+                key: REGISTERS_KEY.to_string(),
+            })
+        },
+        KV_READ_RETRY,
+        Box::new(move |node, response| {
+            let snapshot = match response.map(|response| &response.body) {
+                Ok(MessageBody::Kv(Kv::ReadOk { value, .. })) => Some(value.clone()),
+                // No transaction has written anything yet
+                Err(MaelstromError {
+                    code: ErrorCode::KeyDoesNotExist,
+                    ..
+                }) => None,
+                Err(MaelstromError { code, text }) => {
+                    reply_txn_error(node, &request, code, &text);
+                    return Ok(());
+                }
+                Ok(body) => return Err(format!("Unexpected reply to read: {:?}", body).into()),
+            };
+            commit(node, request, snapshot, attempt);
+            Ok(())
+        }),
+        Box::new(move |node| {
+            reply_txn_error(
+                node,
+                &timed_out,
+                ErrorCode::Timeout,
+                "lin-kv read timed out",
+            )
+        }),
+    );
+    if let Err(e) = sent {
+        node.log_error(&format!("Failed to read registers: {}", e));
+    }
+}
+
+fn commit(
+    node: &Arc<Node>,
+    request: Message<MessageBody>,
+    snapshot: Option<Snapshot>,
+    attempt: u32,
+) {
+    let MessageBody::Txn { txn, .. } = &request.body else {
+        return;
+    };
+    let from = snapshot.clone().unwrap_or_default();
+    let mut registers: Registers = from.iter().copied().collect();
+    let completed = apply(&mut registers, txn);
+    let to: Snapshot = registers.into_iter().collect();
+    if to == from {
+        // Nothing to write, and the snapshot was a committed state
+        reply_txn_ok(node, &request, completed);
+        return;
+    }
+    let timed_out = request.clone();
+    let sent = node.rpc_with_timeout(
+        &NodeId::from(LIN_KV),
+        |msg_id| {
+            MessageBody::Kv(Kv::Cas {
+                msg_id,
+                key: REGISTERS_KEY.to_string(),
+                from,
+                to,
+                create_if_not_exists: snapshot.is_none(),
+            })
+        },
+        KV_CAS_RETRY,
+        Box::new(move |node, response| {
+            match response.map(|response| &response.body) {
+                Ok(MessageBody::Kv(Kv::CasOk { .. })) => reply_txn_ok(node, &request, completed),
+                // Another transaction committed since our read
+                Err(MaelstromError {
+                    code: ErrorCode::PreconditionFailed | ErrorCode::KeyDoesNotExist,
+                    ..
+                }) if attempt < TXN_ATTEMPTS => run_txn(node, request, attempt + 1),
+                Err(MaelstromError {
+                    code: ErrorCode::PreconditionFailed | ErrorCode::KeyDoesNotExist,
+                    ..
+                }) => reply_txn_error(
+                    node,
+                    &request,
+                    ErrorCode::TxnConflict,
+                    "Transaction kept conflicting with concurrent commits",
+                ),
+                Err(MaelstromError { code, text }) => reply_txn_error(node, &request, code, &text),
+                Ok(body) => return Err(format!("Unexpected reply to cas: {:?}", body).into()),
+            }
+            Ok(())
+        }),
+        // The cas may or may not have applied
+        Box::new(move |node| {
+            reply_txn_error(node, &timed_out, ErrorCode::Timeout, "lin-kv cas timed out")
+        }),
+    );
+    if let Err(e) = sent {
+        node.log_error(&format!("Failed to commit txn: {}", e));
+    }
+}
+
+fn main() -> Result<()> {
+    let node = Node::init(State::default()).map_err(|e| anyhow!(e))?;
+    if node.node_ids.len() > 1 {
+        node.log("Committing transactions through lin-kv");
+        node.register("txn", handle_txn_replicated);
+    } else {
+        node.register("txn", handle_txn);
+    }
+    node.run();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom_node::testing::Network;
+    use std::thread;
+
+    const TXN: &str = r#"{"src":"c1","dest":"n0","body":{"type":"txn","msg_id":7,"txn":[["r",1,null],["w",1,5]]}}"#;
+
+    fn cluster() -> Network<State, MessageBody> {
+        Network::new(2, State::default, |node: &Arc<Node>| {
+            node.register("txn", handle_txn_replicated);
+        })
+    }
+
+    fn from_lin_kv(body: &str) -> String {
+        format!(r#"{{"src":"lin-kv","dest":"n0","body":{}}}"#, body)
+    }
+
+    fn read(msg_id: MsgId) -> String {
+        format!(
+            r#"{{"src":"n0","dest":"lin-kv","body":{{"type":"read","msg_id":{},"key":"registers"}}}}"#,
+            msg_id
+        )
+    }
+
+    fn cas(msg_id: MsgId, from: u64) -> String {
+        format!(
+            r#"{{"src":"n0","dest":"lin-kv","body":{{"type":"cas","msg_id":{},"key":"registers","from":[[1,{}]],"to":[[1,5]],"create_if_not_exists":false}}}}"#,
+            msg_id, from
+        )
+    }
+
+    /// Answers the read of `attempt`, counting from 0, with register 1 at
+    /// `from`, and checks the cas that follows.
+    fn read_then_cas(network: &mut Network<State, MessageBody>, attempt: u64, from: u64) {
+        let read_id = 2 * attempt + 1;
+        assert_eq!(network.take_outbox(), [read(read_id)]);
+        network.send(&from_lin_kv(&format!(
+            r#"{{"type":"read_ok","in_reply_to":{},"value":[[1,{}]]}}"#,
+            read_id, from
+        )));
+        network.deliver_all();
+        assert_eq!(network.take_outbox(), [cas(read_id + 1, from)]);
+    }
+
+    fn lose_cas(network: &mut Network<State, MessageBody>, attempt: u64) {
+        network.send(&from_lin_kv(&format!(
+            r#"{{"type":"error","in_reply_to":{},"code":22,"text":"expected other value"}}"#,
+            2 * attempt + 2
+        )));
+        network.deliver_all();
+    }
+
+    #[test]
+    fn lost_cas_reruns_the_txn_on_a_fresh_read() {
+        let mut network = cluster();
+        network.send(TXN);
+        network.deliver_all();
+        read_then_cas(&mut network, 0, 3);
+        lose_cas(&mut network, 0);
+        read_then_cas(&mut network, 1, 4);
+        network.send(&from_lin_kv(r#"{"type":"cas_ok","in_reply_to":4}"#));
+        network.deliver_all();
+
+        assert_eq!(
+            network.take_outbox(),
+            [
+                r#"{"src":"n0","dest":"c1","body":{"type":"txn_ok","in_reply_to":7,"txn":[["r",1,4],["w",1,5]]}}"#
+            ]
+        );
+    }
+
+    #[test]
+    fn txn_losing_every_cas_aborts_with_txn_conflict() {
+        let mut network = cluster();
+        network.send(TXN);
+        network.deliver_all();
+        for attempt in 0..TXN_ATTEMPTS as u64 {
+            read_then_cas(&mut network, attempt, attempt);
+            lose_cas(&mut network, attempt);
+        }
+
+        assert_eq!(
+            network.take_outbox(),
+            [
+                r#"{"src":"n0","dest":"c1","body":{"type":"error","in_reply_to":7,"code":30,"text":"Transaction kept conflicting with concurrent commits"}}"#
+            ]
+        );
+    }
+
+    #[test]
+    fn unanswered_cas_times_out_without_a_resend() {
+        let mut network = cluster();
+        network.send(TXN);
+        network.deliver_all();
+        read_then_cas(&mut network, 0, 3);
+        thread::sleep(KV_CAS_RETRY.timeout + Duration::from_millis(50));
+        network.tick(|_| {});
+        network.deliver_all();
+
+        assert_eq!(
+            network.take_outbox(),
+            [
+                r#"{"src":"n0","dest":"c1","body":{"type":"error","in_reply_to":7,"code":0,"text":"lin-kv cas timed out"}}"#
+            ]
+        );
+    }
+}
@@ -0,0 +1,36 @@
+//! Feeds arbitrary bytes to a node's stdin and reads messages until EOF.
+//! Whatever the input, `receive` has to return an error rather than panic
+//! or hang. Needs a nightly toolchain and cargo-fuzz:
+//!
+//! ```sh
+//! cargo install cargo-fuzz
+//! cd maelstrom-node/fuzz
+//! cargo +nightly fuzz run receive
+//! ```
+//!
+//! Crashing inputs end up in `artifacts/receive/`; replay one with
+//! `cargo +nightly fuzz run receive <file>`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use maelstrom_node::{KvBody, Node, NodeId, ReceiveError};
+use std::io::{self, Cursor};
+
+fuzz_target!(|data: &[u8]| {
+    let node = Node::<(), KvBody>::with_io(
+        &NodeId::from("n1"),
+        vec![NodeId::from("n1")],
+        (),
+        Box::new(Cursor::new(data.to_vec())),
+        Box::new(io::sink()),
+    );
+    // Small enough that oversized lines are exercised too
+    node.set_max_line_length(4096);
+    // Every call consumes input or reports EOF, so this ends
+    loop {
+        if let Err(ReceiveError::Eof) = node.receive() {
+            break;
+        }
+    }
+});
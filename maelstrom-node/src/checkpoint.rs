@@ -0,0 +1,86 @@
+//! Saving a node's application state and loading it back, for crash
+//! recovery experiments.
+
+use crate::message::Body;
+use crate::node::Node;
+use crate::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Application state that can be checkpointed. State usually lives behind
+/// locks and atomics, so it is copied out into a plain serializable
+/// `NodeState` first.
+pub trait Checkpoint {
+    type NodeState: Serialize + DeserializeOwned;
+
+    fn save(&self) -> Self::NodeState;
+
+    /// Brings the live state in line with a saved one.
+    fn load(&self, saved: Self::NodeState);
+}
+
+impl<S: Checkpoint, B: Body> Node<S, B> {
+    /// The node's application state, encoded with bincode.
+    pub fn snapshot(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&self.state.save())?)
+    }
+
+    /// Loads application state from a [`Node::snapshot`].
+    pub fn restore(&self, snapshot: &[u8]) -> Result<()> {
+        self.state.load(bincode::deserialize(snapshot)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{MsgId, NodeId};
+    use crate::sync::lock;
+    use serde::Deserialize;
+    use std::collections::BTreeSet;
+    use std::sync::Mutex;
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct NoBody {}
+
+    impl Body for NoBody {
+        fn type_tag(&self) -> &'static str {
+            ""
+        }
+        fn msg_id(&self) -> Option<MsgId> {
+            None
+        }
+        fn in_reply_to(&self) -> Option<MsgId> {
+            None
+        }
+    }
+
+    #[derive(Default)]
+    struct Elements(Mutex<BTreeSet<u64>>);
+
+    impl Checkpoint for Elements {
+        type NodeState = Vec<u64>;
+
+        fn save(&self) -> Vec<u64> {
+            lock(&self.0).iter().copied().collect()
+        }
+
+        fn load(&self, saved: Vec<u64>) {
+            *lock(&self.0) = saved.into_iter().collect();
+        }
+    }
+
+    #[test]
+    fn restore_brings_back_a_snapshot() {
+        let id = NodeId::from("n1");
+        let node = Node::<Elements, NoBody>::new(&id, vec![], Elements::default());
+        lock(&node.state.0).extend([3, 1, 2]);
+        let snapshot = node.snapshot().unwrap();
+
+        let restarted = Node::<Elements, NoBody>::new(&id, vec![], Elements::default());
+        restarted.restore(&snapshot).unwrap();
+        assert_eq!(*lock(&restarted.state.0), BTreeSet::from([1, 2, 3]));
+        assert!(restarted.restore(&[0xff]).is_err());
+    }
+}
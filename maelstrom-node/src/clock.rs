@@ -0,0 +1,143 @@
+use crate::message::NodeId;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+/// A vector clock: for each node, how many of its local events are known.
+/// Serialized as a map from node id to counter, e.g. `{"n1":3,"n2":1}`;
+/// nodes without events are left out.
+///
+/// Clocks are only partially ordered. `a.happens_before(&b)` means every
+/// event `a` knows of is known to `b` as well, and `b` knows of more;
+/// clocks where neither happens before the other are concurrent.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(transparent)]
+pub struct VectorClock(BTreeMap<NodeId, u64>);
+
+impl VectorClock {
+    pub fn new() -> Self {
+        VectorClock::default()
+    }
+
+    /// How many of `node`'s events this clock knows of.
+    pub fn get(&self, node: &NodeId) -> u64 {
+        self.0.get(node).copied().unwrap_or(0)
+    }
+
+    /// Counts a local event on `node` and returns its new counter.
+    pub fn increment(&mut self, node: &NodeId) -> u64 {
+        let counter = self.0.entry(node.clone()).or_insert(0);
+        *counter += 1;
+        *counter
+    }
+
+    /// Takes in everything `other` knows of, keeping the higher counter for
+    /// each node.
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node, &theirs) in other.0.iter().filter(|(_, &counter)| counter > 0) {
+            let ours = self.0.entry(node.clone()).or_insert(0);
+            *ours = (*ours).max(theirs);
+        }
+    }
+
+    pub fn happens_before(&self, other: &VectorClock) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Less)
+    }
+
+    /// Neither clock happens before the other, and they differ.
+    pub fn concurrent_with(&self, other: &VectorClock) -> bool {
+        self.partial_cmp(other).is_none()
+    }
+}
+
+// A node missing from one clock counts as zero there, so `{}` and
+// `{"n1":0}` are equal.
+impl PartialEq for VectorClock {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for VectorClock {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let (mut behind, mut ahead) = (false, false);
+        for node in self.0.keys().chain(other.0.keys()) {
+            match self.get(node).cmp(&other.get(node)) {
+                Ordering::Less => behind = true,
+                Ordering::Greater => ahead = true,
+                Ordering::Equal => {}
+            }
+        }
+        match (behind, ahead) {
+            (false, false) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Less),
+            (false, true) => Some(Ordering::Greater),
+            (true, true) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock(counters: &[(&str, u64)]) -> VectorClock {
+        VectorClock(
+            counters
+                .iter()
+                .map(|&(node, counter)| (NodeId::from(node), counter))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn increment_counts_local_events() {
+        let mut clock = VectorClock::new();
+        let n1 = NodeId::from("n1");
+        assert_eq!(clock.get(&n1), 0);
+        assert_eq!(clock.increment(&n1), 1);
+        assert_eq!(clock.increment(&n1), 2);
+        assert_eq!(clock.get(&NodeId::from("n2")), 0);
+    }
+
+    #[test]
+    fn merge_keeps_the_higher_counter_per_node() {
+        let mut merged = clock(&[("n1", 3), ("n2", 1)]);
+        merged.merge(&clock(&[("n2", 4), ("n3", 2), ("n4", 0)]));
+        assert_eq!(merged, clock(&[("n1", 3), ("n2", 4), ("n3", 2)]));
+        assert_eq!(
+            serde_json::to_string(&merged).unwrap(),
+            r#"{"n1":3,"n2":4,"n3":2}"#
+        );
+    }
+
+    #[test]
+    fn a_clock_happens_before_the_clocks_that_learned_of_it() {
+        let sent = clock(&[("n1", 2)]);
+        let mut received = clock(&[("n2", 1)]);
+        assert!(sent.concurrent_with(&received));
+
+        received.merge(&sent);
+        received.increment(&NodeId::from("n2"));
+        assert!(sent.happens_before(&received));
+        assert!(!received.happens_before(&sent));
+        assert!(!sent.concurrent_with(&received));
+    }
+
+    #[test]
+    fn equal_clocks_are_neither_before_nor_concurrent() {
+        let a = clock(&[("n1", 1)]);
+        let b = clock(&[("n1", 1), ("n2", 0)]);
+        assert_eq!(a, b);
+        assert!(!a.happens_before(&b));
+        assert!(!a.concurrent_with(&b));
+        assert!(!VectorClock::new().happens_before(&VectorClock::new()));
+    }
+
+    #[test]
+    fn clocks_deserialize_from_a_map() {
+        let parsed: VectorClock = serde_json::from_str(r#"{"n1":2,"n3":5}"#).unwrap();
+        assert_eq!(parsed, clock(&[("n1", 2), ("n3", 5)]));
+        assert!(clock(&[("n1", 1)]).happens_before(&parsed));
+    }
+}
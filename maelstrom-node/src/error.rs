@@ -0,0 +1,244 @@
+use crate::message::MsgId;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Failures a message handler can run into. Unlike a boxed error the
+/// variant says what went wrong, so poisoned locks and failed sends are told
+/// apart in the log instead of disappearing into a string.
+#[derive(Debug)]
+pub enum NodeError {
+    /// A mutex was poisoned by a panicking thread. Holds the lock's name.
+    LockPoisoned(&'static str),
+    Serialize(serde_json::Error),
+    Io(io::Error),
+    /// A handler was given a message type it does not handle. Holds the
+    /// handler's name.
+    WrongHandler(&'static str),
+    /// Writing a message to stdout failed.
+    Send(Box<dyn Error + Send + Sync>),
+}
+
+impl fmt::Display for NodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeError::LockPoisoned(lock) => write!(f, "Lock on {} is poisoned", lock),
+            NodeError::Serialize(e) => write!(f, "Failed to serialize: {}", e),
+            NodeError::Io(e) => write!(f, "I/O error: {}", e),
+            NodeError::WrongHandler(handler) => {
+                write!(f, "{} called on different message", handler)
+            }
+            NodeError::Send(e) => write!(f, "Failed to send: {}", e),
+        }
+    }
+}
+
+impl Error for NodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            NodeError::Serialize(e) => Some(e),
+            NodeError::Io(e) => Some(e),
+            NodeError::Send(e) => Some(e.as_ref()),
+            NodeError::LockPoisoned(_) | NodeError::WrongHandler(_) => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for NodeError {
+    fn from(e: serde_json::Error) -> Self {
+        NodeError::Serialize(e)
+    }
+}
+
+impl From<io::Error> for NodeError {
+    fn from(e: io::Error) -> Self {
+        NodeError::Io(e)
+    }
+}
+
+/// Why [`crate::Node::receive`] did not return a message.
+#[derive(Debug)]
+pub enum ReceiveError {
+    /// Stdin was closed; no further messages will arrive.
+    Eof,
+    /// The line was not a message this node understands. Safe to skip.
+    Malformed(serde_json::Error),
+    /// A line longer than the limit in bytes, see
+    /// [`crate::Node::set_max_line_length`]. It was skipped without being
+    /// buffered whole; safe to carry on with the next one.
+    TooLong(usize),
+    /// Reading stdin failed, e.g. on a line that is not valid UTF-8. The
+    /// caller decides whether to retry or shut down.
+    Io(io::Error),
+}
+
+impl fmt::Display for ReceiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReceiveError::Eof => f.write_str("Stdin closed"),
+            ReceiveError::Malformed(e) => write!(f, "Malformed message: {}", e),
+            ReceiveError::TooLong(limit) => {
+                write!(f, "Skipped a message longer than {} bytes", limit)
+            }
+            ReceiveError::Io(e) => write!(f, "Failed to read message: {}", e),
+        }
+    }
+}
+
+impl Error for ReceiveError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ReceiveError::Eof | ReceiveError::TooLong(_) => None,
+            ReceiveError::Malformed(e) => Some(e),
+            ReceiveError::Io(e) => Some(e),
+        }
+    }
+}
+
+/// Maelstrom's standard error codes, see `doc/protocol.md`. Codes without a
+/// name of their own, such as custom codes of 1000 and up, are kept as `Other`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    Timeout,
+    NodeNotFound,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed,
+    TxnConflict,
+    Other(u64),
+}
+
+impl ErrorCode {
+    pub fn code(self) -> u64 {
+        match self {
+            ErrorCode::Timeout => 0,
+            ErrorCode::NodeNotFound => 1,
+            ErrorCode::NotSupported => 10,
+            ErrorCode::TemporarilyUnavailable => 11,
+            ErrorCode::MalformedRequest => 12,
+            ErrorCode::Crash => 13,
+            ErrorCode::Abort => 14,
+            ErrorCode::KeyDoesNotExist => 20,
+            ErrorCode::KeyAlreadyExists => 21,
+            ErrorCode::PreconditionFailed => 22,
+            ErrorCode::TxnConflict => 30,
+            ErrorCode::Other(code) => code,
+        }
+    }
+
+    /// Whether the error guarantees the operation did not take place.
+    /// `timeout`, `crash` and unknown codes are indefinite.
+    pub fn is_definite(self) -> bool {
+        !matches!(
+            self,
+            ErrorCode::Timeout | ErrorCode::Crash | ErrorCode::Other(_)
+        )
+    }
+}
+
+impl From<u64> for ErrorCode {
+    fn from(code: u64) -> Self {
+        match code {
+            0 => ErrorCode::Timeout,
+            1 => ErrorCode::NodeNotFound,
+            10 => ErrorCode::NotSupported,
+            11 => ErrorCode::TemporarilyUnavailable,
+            12 => ErrorCode::MalformedRequest,
+            13 => ErrorCode::Crash,
+            14 => ErrorCode::Abort,
+            20 => ErrorCode::KeyDoesNotExist,
+            21 => ErrorCode::KeyAlreadyExists,
+            22 => ErrorCode::PreconditionFailed,
+            30 => ErrorCode::TxnConflict,
+            code => ErrorCode::Other(code),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorCode::Timeout => "timeout",
+            ErrorCode::NodeNotFound => "node-not-found",
+            ErrorCode::NotSupported => "not-supported",
+            ErrorCode::TemporarilyUnavailable => "temporarily-unavailable",
+            ErrorCode::MalformedRequest => "malformed-request",
+            ErrorCode::Crash => "crash",
+            ErrorCode::Abort => "abort",
+            ErrorCode::KeyDoesNotExist => "key-does-not-exist",
+            ErrorCode::KeyAlreadyExists => "key-already-exists",
+            ErrorCode::PreconditionFailed => "precondition-failed",
+            ErrorCode::TxnConflict => "txn-conflict",
+            ErrorCode::Other(code) => return write!(f, "error {}", code),
+        };
+        f.write_str(name)
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        u64::deserialize(deserializer).map(ErrorCode::from)
+    }
+}
+
+/// The `error` reply any request may receive instead of its `*_ok`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename = "error")]
+pub struct ErrorBody {
+    pub in_reply_to: MsgId,
+    pub code: ErrorCode,
+    pub text: String,
+}
+
+/// An `error` reply to an RPC, handed to its [`crate::Callback`] in place
+/// of the reply message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaelstromError {
+    pub code: ErrorCode,
+    pub text: String,
+}
+
+impl fmt::Display for MaelstromError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.text)
+    }
+}
+
+impl Error for MaelstromError {}
+
+impl From<ErrorBody> for MaelstromError {
+    fn from(body: ErrorBody) -> Self {
+        MaelstromError {
+            code: body.code,
+            text: body.text,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_codes_round_trip_as_numbers() {
+        let json = r#"{"type":"error","in_reply_to":5,"code":10,"text":"nope"}"#;
+        let body: ErrorBody = serde_json::from_str(json).unwrap();
+        assert_eq!(body.code, ErrorCode::NotSupported);
+        assert_eq!(serde_json::to_string(&body).unwrap(), json);
+        assert_eq!(ErrorCode::from(1005), ErrorCode::Other(1005));
+        assert!(!ErrorCode::Timeout.is_definite());
+        assert!(ErrorCode::KeyDoesNotExist.is_definite());
+    }
+}
@@ -0,0 +1,250 @@
+use crate::message::{MsgId, NodeId};
+use crate::sync::lock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A source of ids that never repeat. Every call returns a fresh one, from
+/// any thread.
+pub trait IdGenerator: Send + Sync {
+    type Id;
+    fn next_id(&self) -> Self::Id;
+}
+
+/// 1, 2, 3, ... Unique within one generator only; what [`crate::Node`]
+/// uses for `msg_id`s.
+#[derive(Debug)]
+pub struct Monotonic(AtomicU64);
+
+impl Monotonic {
+    pub fn new() -> Self {
+        Monotonic(AtomicU64::new(1))
+    }
+}
+
+impl Default for Monotonic {
+    fn default() -> Self {
+        Monotonic::new()
+    }
+}
+
+impl IdGenerator for Monotonic {
+    type Id = MsgId;
+
+    fn next_id(&self) -> MsgId {
+        self.0.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+/// `"<node id>-<n>"`, unique across a cluster because node ids are.
+#[derive(Debug)]
+pub struct NodePrefixed {
+    node_id: NodeId,
+    seq: Monotonic,
+}
+
+impl NodePrefixed {
+    pub fn new(node_id: &NodeId) -> Self {
+        NodePrefixed {
+            node_id: node_id.clone(),
+            seq: Monotonic::new(),
+        }
+    }
+}
+
+impl IdGenerator for NodePrefixed {
+    type Id = String;
+
+    fn next_id(&self) -> String {
+        format!("{}-{}", self.node_id, self.seq.next_id())
+    }
+}
+
+const SNOWFLAKE_NODE_BITS: u32 = 10;
+const SNOWFLAKE_SEQ_BITS: u32 = 12;
+const MAX_SNOWFLAKE_SEQ: u64 = (1 << SNOWFLAKE_SEQ_BITS) - 1;
+// Snowflake timestamps count from 2025-01-01, which leaves 41 bits of
+// milliseconds enough for decades
+const SNOWFLAKE_EPOCH: Duration = Duration::from_millis(1_735_689_600_000);
+
+/// Milliseconds since [`SNOWFLAKE_EPOCH`], 0 for a clock set before it.
+fn snowflake_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH + SNOWFLAKE_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// A u64 packing milliseconds since 2025 (41 bits), the node's index
+/// (10 bits) and a per-millisecond sequence (12 bits). Unique across a
+/// cluster of up to 1024 nodes without coordination, and strictly
+/// increasing on each node, so ids sort roughly by creation time.
+///
+/// If the clock steps back, ids keep the last timestamp and count the
+/// sequence up instead of repeating one. Once a millisecond's 4096
+/// sequence numbers are used up, the generator waits for the clock to
+/// reach the next millisecond.
+pub struct Snowflake {
+    node: u64,
+    clock: Box<dyn Fn() -> u64 + Send + Sync>,
+    // The timestamp and sequence number of the last id
+    last: Mutex<(u64, u64)>,
+}
+
+impl Snowflake {
+    /// A generator for the node `node_id`, which must have a numeric
+    /// suffix below 1024, e.g. `"n3"`.
+    pub fn new(node_id: &NodeId) -> crate::Result<Self> {
+        Snowflake::with_clock(node_id, snowflake_millis)
+    }
+
+    fn with_clock(
+        node_id: &NodeId,
+        clock: impl Fn() -> u64 + Send + Sync + 'static,
+    ) -> crate::Result<Self> {
+        let node = node_id
+            .index()
+            .filter(|index| *index < 1 << SNOWFLAKE_NODE_BITS)
+            .ok_or_else(|| format!("{} has no node index below 1024", node_id))?;
+        Ok(Snowflake {
+            node,
+            clock: Box::new(clock),
+            last: Mutex::new((0, 0)),
+        })
+    }
+
+    pub fn next(&self) -> u64 {
+        let mut last = lock(&self.last);
+        let (millis, seq) = *last;
+        let mut now = (self.clock)();
+        *last = if now > millis {
+            (now, 0)
+        } else if seq < MAX_SNOWFLAKE_SEQ {
+            (millis, seq + 1)
+        } else {
+            while now <= millis {
+                std::hint::spin_loop();
+                now = (self.clock)();
+            }
+            (now, 0)
+        };
+        let (millis, seq) = *last;
+        millis << (SNOWFLAKE_NODE_BITS + SNOWFLAKE_SEQ_BITS) | self.node << SNOWFLAKE_SEQ_BITS | seq
+    }
+}
+
+impl IdGenerator for Snowflake {
+    type Id = u64;
+
+    fn next_id(&self) -> u64 {
+        self.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Draws `per_thread` ids on each of four threads and returns them in
+    /// the order each thread got them.
+    fn draw<G>(generator: G, per_thread: usize) -> Vec<Vec<G::Id>>
+    where
+        G: IdGenerator + 'static,
+        G::Id: Send + 'static,
+    {
+        let generator = Arc::new(generator);
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let generator = Arc::clone(&generator);
+                thread::spawn(move || (0..per_thread).map(|_| generator.next_id()).collect())
+            })
+            .collect();
+        threads.into_iter().map(|t| t.join().unwrap()).collect()
+    }
+
+    fn assert_unique_and_increasing<T: Ord + std::hash::Hash + Clone>(drawn: &[Vec<T>]) {
+        let mut seen = HashSet::new();
+        for ids in drawn {
+            assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+            for id in ids {
+                assert!(seen.insert(id.clone()));
+            }
+        }
+    }
+
+    #[test]
+    fn monotonic_ids_are_unique_and_increasing() {
+        assert_eq!(Monotonic::new().next_id(), 1);
+        assert_unique_and_increasing(&draw(Monotonic::new(), 10_000));
+    }
+
+    #[test]
+    fn node_prefixed_ids_are_unique_and_count_up() {
+        let drawn = draw(NodePrefixed::new(&NodeId::from("n2")), 10_000);
+        let seqs: Vec<Vec<u64>> = drawn
+            .iter()
+            .map(|ids| {
+                ids.iter()
+                    .map(|id| id.strip_prefix("n2-").unwrap().parse().unwrap())
+                    .collect()
+            })
+            .collect();
+        assert_unique_and_increasing(&seqs);
+    }
+
+    #[test]
+    fn snowflake_ids_are_unique_and_increasing_across_nodes() {
+        // More than the 4096 sequence numbers of one millisecond
+        let mut drawn = draw(Snowflake::new(&NodeId::from("n1")).unwrap(), 10_000);
+        assert_unique_and_increasing(&drawn);
+        drawn.extend(draw(Snowflake::new(&NodeId::from("n2")).unwrap(), 10_000));
+        let all: HashSet<u64> = drawn.iter().flatten().copied().collect();
+        assert_eq!(all.len(), 80_000);
+    }
+
+    /// A clock reading `readings` in turn, then the last one forever.
+    fn clock(readings: Vec<u64>) -> impl Fn() -> u64 + Send + Sync {
+        let calls = AtomicU64::new(0);
+        move || {
+            let call = calls.fetch_add(1, Ordering::SeqCst) as usize;
+            readings[call.min(readings.len() - 1)]
+        }
+    }
+
+    fn unpack(id: u64) -> (u64, u64, u64) {
+        (id >> 22, (id >> 12) & 0x3ff, id & 0xfff)
+    }
+
+    #[test]
+    fn snowflake_keeps_counting_when_the_clock_steps_back() {
+        let ids = Snowflake::with_clock(&NodeId::from("n3"), clock(vec![10, 7, 12])).unwrap();
+        let drawn: Vec<u64> = (0..3).map(|_| ids.next()).collect();
+        assert_eq!(
+            drawn.iter().copied().map(unpack).collect::<Vec<_>>(),
+            [(10, 3, 0), (10, 3, 1), (12, 3, 0)]
+        );
+        assert!(drawn.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn snowflake_waits_for_the_next_millisecond_when_the_sequence_runs_out() {
+        // The clock reads 5 for the first 4096 ids and three readings while
+        // the generator waits, then 6
+        let mut readings = vec![5; 4096 + 3];
+        readings.push(6);
+        let ids = Snowflake::with_clock(&NodeId::from("n1"), clock(readings)).unwrap();
+        let drawn: Vec<u64> = (0..4097).map(|_| ids.next()).collect();
+        assert_eq!(unpack(drawn[4095]), (5, 1, 4095));
+        assert_eq!(unpack(drawn[4096]), (6, 1, 0));
+        assert!(drawn.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn snowflake_needs_a_small_node_index() {
+        assert!(Snowflake::new(&NodeId::from("n1023")).is_ok());
+        assert!(Snowflake::new(&NodeId::from("n1024")).is_err());
+        assert!(Snowflake::new(&NodeId::from("lin-kv")).is_err());
+    }
+}
@@ -0,0 +1,432 @@
+//! Client side of Maelstrom's key-value services.
+//!
+//! Embed [`KvBody`] in a challenge's body enum as the last variant, marked
+//! `#[serde(untagged)]`, so replies from the services parse alongside the
+//! challenge's own messages.
+
+use crate::error::{ErrorCode, MaelstromError};
+use crate::message::{Body, Message, MsgId, NodeId};
+use crate::node::{Node, RetryPolicy};
+use crate::sync::lock;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A linearizable key-value store.
+pub const LIN_KV: &str = "lin-kv";
+/// A sequentially consistent key-value store.
+pub const SEQ_KV: &str = "seq-kv";
+/// A last-write-wins key-value store.
+pub const LWW_KV: &str = "lww-kv";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum KvBody<K = String, V = u64> {
+    #[serde(rename = "read")]
+    Read { msg_id: MsgId, key: K },
+    #[serde(rename = "read_ok")]
+    ReadOk { in_reply_to: MsgId, value: V },
+    #[serde(rename = "write")]
+    Write { msg_id: MsgId, key: K, value: V },
+    #[serde(rename = "write_ok")]
+    WriteOk { in_reply_to: MsgId },
+    #[serde(rename = "cas")]
+    Cas {
+        msg_id: MsgId,
+        key: K,
+        from: V,
+        to: V,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    #[serde(rename = "cas_ok")]
+    CasOk { in_reply_to: MsgId },
+    #[serde(rename = "error")]
+    Error {
+        in_reply_to: MsgId,
+        code: ErrorCode,
+        text: String,
+    },
+}
+
+impl<K: Serialize + DeserializeOwned, V: Serialize + DeserializeOwned> Body for KvBody<K, V> {
+    fn type_tag(&self) -> &'static str {
+        match self {
+            Self::Read { .. } => "read",
+            Self::ReadOk { .. } => "read_ok",
+            Self::Write { .. } => "write",
+            Self::WriteOk { .. } => "write_ok",
+            Self::Cas { .. } => "cas",
+            Self::CasOk { .. } => "cas_ok",
+            Self::Error { .. } => "error",
+        }
+    }
+    fn msg_id(&self) -> Option<MsgId> {
+        match self {
+            Self::Read { msg_id, .. } => Some(*msg_id),
+            Self::Write { msg_id, .. } => Some(*msg_id),
+            Self::Cas { msg_id, .. } => Some(*msg_id),
+            _ => None,
+        }
+    }
+    fn in_reply_to(&self) -> Option<MsgId> {
+        match self {
+            Self::ReadOk { in_reply_to, .. } => Some(*in_reply_to),
+            Self::WriteOk { in_reply_to } => Some(*in_reply_to),
+            Self::CasOk { in_reply_to } => Some(*in_reply_to),
+            Self::Error { in_reply_to, .. } => Some(*in_reply_to),
+            _ => None,
+        }
+    }
+}
+
+impl<K: Clone, V: Clone> KvBody<K, V> {
+    /// The same request under a new `msg_id`, for passing a client's request
+    /// on to a service. Replies are returned unchanged.
+    pub fn with_msg_id(&self, msg_id: MsgId) -> Self {
+        match self {
+            Self::Read { key, .. } => Self::Read {
+                msg_id,
+                key: key.clone(),
+            },
+            Self::Write { key, value, .. } => Self::Write {
+                msg_id,
+                key: key.clone(),
+                value: value.clone(),
+            },
+            Self::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+                ..
+            } => Self::Cas {
+                msg_id,
+                key: key.clone(),
+                from: from.clone(),
+                to: to.clone(),
+                create_if_not_exists: *create_if_not_exists,
+            },
+            other => other.clone(),
+        }
+    }
+}
+
+/// Answers a client's `request` with `response`, the reply `service` sent
+/// to the copy of it forwarded there. For nodes whose clients speak the
+/// same read/write/cas protocol as the service, so requests are passed on
+/// and replies relayed as they are. Errors from the service are answered
+/// with [`Node::reply_error`] by the caller, which keeps their code.
+pub fn relay<S, K, V>(
+    node: &Node<S, KvBody<K, V>>,
+    service: &str,
+    request: &Message<KvBody<K, V>>,
+    response: &KvBody<K, V>,
+) -> crate::Result<()>
+where
+    K: Serialize + DeserializeOwned + Debug,
+    V: Serialize + DeserializeOwned + Clone + Debug,
+{
+    match response {
+        KvBody::ReadOk { value, .. } => node.reply(request, |in_reply_to| KvBody::ReadOk {
+            in_reply_to,
+            value: value.clone(),
+        }),
+        KvBody::WriteOk { .. } => {
+            node.reply(request, |in_reply_to| KvBody::WriteOk { in_reply_to })
+        }
+        KvBody::CasOk { .. } => node.reply(request, |in_reply_to| KvBody::CasOk { in_reply_to }),
+        _ => Err(format!("Unexpected reply from {}: {:?}", service, response).into()),
+    }
+}
+
+/// A challenge body that embeds [`KvBody`], so [`Node::kv_update`] can talk
+/// to the services on the challenge's behalf.
+pub trait KvMessage<V>: Body + From<KvBody<String, V>> {
+    fn as_kv(&self) -> Option<&KvBody<String, V>>;
+}
+
+impl<V: Serialize + DeserializeOwned> KvMessage<V> for KvBody<String, V> {
+    fn as_kv(&self) -> Option<&KvBody<String, V>> {
+        Some(self)
+    }
+}
+
+/// How many times [`Node::kv_update`] starts over after losing a cas race
+/// before it gives up.
+pub const KV_UPDATE_ATTEMPTS: u32 = 10;
+
+const KV_READ_RETRY: RetryPolicy = RetryPolicy {
+    timeout: Duration::from_millis(500),
+    max_retries: 2,
+};
+// A resent cas that already applied would fail its precondition and be
+// retried on top of itself, applying the update twice. Never resend it.
+const KV_CAS_RETRY: RetryPolicy = RetryPolicy {
+    timeout: Duration::from_millis(500),
+    max_retries: 0,
+};
+
+type UpdateFn<V> = Box<dyn Fn(Option<V>) -> V + Send>;
+type DoneFn<S, B, V> = Box<dyn FnOnce(&Arc<Node<S, B>>, Result<V, ErrorCode>) + Send>;
+
+/// An update in flight. Shared between an RPC's reply and timeout
+/// callbacks, and taken by whichever of them finishes it.
+struct KvUpdate<S, B, V> {
+    service: NodeId,
+    key: String,
+    update: UpdateFn<V>,
+    attempts: u32,
+    done: Option<DoneFn<S, B, V>>,
+}
+
+type SharedUpdate<S, B, V> = Arc<Mutex<KvUpdate<S, B, V>>>;
+
+impl<S: 'static, B: 'static> Node<S, B> {
+    /// Replaces the value under `key` in `service` with `update(current)`,
+    /// where `current` is `None` if the key does not exist yet. Reads the
+    /// value, then writes the new one with a cas, and starts over whenever
+    /// the value moved in between. Calls `done` with the value written, or
+    /// with `timeout` if the service did not answer or the cas kept losing
+    /// for [`KV_UPDATE_ATTEMPTS`] rounds.
+    pub fn kv_update<V>(
+        self: &Arc<Self>,
+        service: &str,
+        key: &str,
+        update: impl Fn(Option<V>) -> V + Send + 'static,
+        done: impl FnOnce(&Arc<Self>, Result<V, ErrorCode>) + Send + 'static,
+    ) where
+        B: KvMessage<V>,
+        V: Copy + Default + Send + 'static,
+    {
+        kv_read(
+            self,
+            Arc::new(Mutex::new(KvUpdate {
+                service: NodeId::from(service),
+                key: key.to_string(),
+                update: Box::new(update),
+                attempts: 0,
+                done: Some(Box::new(done)),
+            })),
+        );
+    }
+}
+
+fn kv_finish<S, B, V>(
+    node: &Arc<Node<S, B>>,
+    op: &SharedUpdate<S, B, V>,
+    outcome: Result<V, ErrorCode>,
+) {
+    let done = lock(op).done.take();
+    if let Some(done) = done {
+        done(node, outcome);
+    }
+}
+
+fn kv_read<S: 'static, B: KvMessage<V> + 'static, V: Copy + Default + Send + 'static>(
+    node: &Arc<Node<S, B>>,
+    op: SharedUpdate<S, B, V>,
+) {
+    let (service, key) = {
+        let op = lock(&op);
+        (op.service.clone(), op.key.clone())
+    };
+    let on_timeout = Arc::clone(&op);
+    let on_error = Arc::clone(&op);
+    let sent = node.rpc_with_timeout(
+        &service,
+        |msg_id| KvBody::Read { msg_id, key }.into(),
+        KV_READ_RETRY,
+        Box::new(move |node, response| {
+            match response.map(|response| response.body.as_kv()) {
+                Ok(Some(KvBody::ReadOk { value, .. })) => kv_cas(node, op, Some(*value)),
+                Err(MaelstromError {
+                    code: ErrorCode::KeyDoesNotExist,
+                    ..
+                }) => kv_cas(node, op, None),
+                Err(MaelstromError { code, .. }) => kv_finish(node, &op, Err(code)),
+                Ok(_) => {
+                    kv_finish(node, &op, Err(ErrorCode::Crash));
+                    return Err("Unexpected reply to kv read".into());
+                }
+            }
+            Ok(())
+        }),
+        Box::new(move |node| kv_finish(node, &on_timeout, Err(ErrorCode::Timeout))),
+    );
+    if let Err(e) = sent {
+        node.log_error(&format!("Failed to send kv read: {}", e));
+        kv_finish(node, &on_error, Err(ErrorCode::TemporarilyUnavailable));
+    }
+}
+
+fn kv_cas<S: 'static, B: KvMessage<V> + 'static, V: Copy + Default + Send + 'static>(
+    node: &Arc<Node<S, B>>,
+    op: SharedUpdate<S, B, V>,
+    current: Option<V>,
+) {
+    let (service, key, to) = {
+        let op = lock(&op);
+        (op.service.clone(), op.key.clone(), (op.update)(current))
+    };
+    let on_timeout = Arc::clone(&op);
+    let on_error = Arc::clone(&op);
+    let sent = node.rpc_with_timeout(
+        &service,
+        |msg_id| {
+            KvBody::Cas {
+                msg_id,
+                key,
+                from: current.unwrap_or_default(),
+                to,
+                create_if_not_exists: current.is_none(),
+            }
+            .into()
+        },
+        KV_CAS_RETRY,
+        Box::new(move |node, response| {
+            match response.map(|response| response.body.as_kv()) {
+                Ok(Some(KvBody::CasOk { .. })) => kv_finish(node, &op, Ok(to)),
+                // Someone else's write got in between our read and cas
+                Err(MaelstromError {
+                    code: ErrorCode::PreconditionFailed | ErrorCode::KeyDoesNotExist,
+                    ..
+                }) => {
+                    let attempts = {
+                        let mut op = lock(&op);
+                        op.attempts += 1;
+                        op.attempts
+                    };
+                    if attempts < KV_UPDATE_ATTEMPTS {
+                        kv_read(node, op);
+                    } else {
+                        kv_finish(node, &op, Err(ErrorCode::Timeout));
+                    }
+                }
+                Err(MaelstromError { code, .. }) => kv_finish(node, &op, Err(code)),
+                Ok(_) => {
+                    kv_finish(node, &op, Err(ErrorCode::Crash));
+                    return Err("Unexpected reply to kv cas".into());
+                }
+            }
+            Ok(())
+        }),
+        // The cas may or may not have applied
+        Box::new(move |node| kv_finish(node, &on_timeout, Err(ErrorCode::Timeout))),
+    );
+    if let Err(e) = sent {
+        node.log_error(&format!("Failed to send kv cas: {}", e));
+        kv_finish(node, &on_error, Err(ErrorCode::Crash));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kv_bodies_match_the_service_wire_format() {
+        let cas: KvBody = KvBody::Cas {
+            msg_id: 1,
+            key: "n1".to_string(),
+            from: 2,
+            to: 3,
+            create_if_not_exists: true,
+        };
+        assert_eq!(
+            serde_json::to_string(&cas).unwrap(),
+            r#"{"type":"cas","msg_id":1,"key":"n1","from":2,"to":3,"create_if_not_exists":true}"#
+        );
+        assert_eq!(cas.type_tag(), "cas");
+        assert_eq!(cas.error(), None);
+
+        let error: KvBody = serde_json::from_str(
+            r#"{"type":"error","in_reply_to":2,"code":22,"text":"expected 2, had 4"}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            error,
+            KvBody::Error {
+                code: ErrorCode::PreconditionFailed,
+                ..
+            }
+        ));
+        assert_eq!(error.type_tag(), "error");
+        assert_eq!(
+            error.error().map(|error| error.code),
+            Some(ErrorCode::PreconditionFailed)
+        );
+    }
+
+    #[test]
+    fn relay_answers_the_client_with_the_services_reply() {
+        let output = crate::testing::SharedBuffer::default();
+        let node = Node::with_io(
+            &NodeId::from("n1"),
+            vec![],
+            (),
+            Box::new(std::io::empty()),
+            Box::new(output.clone()),
+        );
+        let request: Message<KvBody<u64, u64>> = Message {
+            src: NodeId::from("c1"),
+            dest: NodeId::from("n1"),
+            lamport: None,
+            body: KvBody::Read { msg_id: 7, key: 1 },
+        };
+        relay(
+            &node,
+            LIN_KV,
+            &request,
+            &KvBody::ReadOk {
+                in_reply_to: 2,
+                value: 5,
+            },
+        )
+        .unwrap();
+        let error = relay(&node, LIN_KV, &request, &KvBody::Read { msg_id: 3, key: 1 });
+        assert!(error.unwrap_err().to_string().contains("lin-kv"));
+
+        node.flush().unwrap();
+        assert_eq!(
+            String::from_utf8(lock(&output.0).clone()).unwrap(),
+            concat!(
+                r#"{"src":"n1","dest":"c1","body":{"type":"read_ok","in_reply_to":7,"value":5}}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn kv_update_starts_over_when_the_cas_loses() {
+        let outcome = Arc::new(Mutex::new(None));
+        let seen = Arc::clone(&outcome);
+        let reply = |body: &str| format!(r#"{{"src":"seq-kv","dest":"n1","body":{}}}"#, body);
+        let lines = [
+            crate::testing::init_line("n1", &["n1"]),
+            reply(r#"{"type":"read_ok","in_reply_to":2,"value":5}"#),
+            reply(r#"{"type":"error","in_reply_to":3,"code":22,"text":"expected 5, had 7"}"#),
+            reply(r#"{"type":"read_ok","in_reply_to":4,"value":7}"#),
+            reply(r#"{"type":"cas_ok","in_reply_to":5}"#),
+        ];
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let output = crate::testing::run_lines(
+            (),
+            |node: &Arc<Node<(), KvBody>>| {
+                node.kv_update(
+                    SEQ_KV,
+                    "n1",
+                    |current| current.unwrap_or(0) + 1,
+                    move |_, result| *lock(&seen) = Some(result),
+                )
+            },
+            &lines,
+        )
+        .unwrap();
+
+        assert_eq!(*lock(&outcome), Some(Ok(8)));
+        assert!(output[4].contains(r#""from":7,"to":8"#), "{}", output[4]);
+    }
+}
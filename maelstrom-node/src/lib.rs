@@ -0,0 +1,33 @@
+//! Shared Maelstrom node scaffolding used by the challenge binaries.
+//!
+//! A challenge defines its own message body enum, implements [`Body`] for it
+//! and keeps its application state in the `S` parameter of [`Node`].
+
+mod checkpoint;
+mod clock;
+mod error;
+mod id;
+pub mod kv;
+mod log;
+mod message;
+mod node;
+mod outbound;
+mod sync;
+pub mod testing;
+pub mod txn;
+
+pub use checkpoint::Checkpoint;
+pub use clock::VectorClock;
+pub use error::{ErrorBody, ErrorCode, MaelstromError, NodeError, ReceiveError};
+pub use id::{IdGenerator, Monotonic, NodePrefixed, Snowflake};
+pub use kv::KvBody;
+pub use log::LogLevel;
+pub use message::{Ack, Body, InitBody, Message, MsgId, NodeId, PROTOCOL_VERSION};
+pub use node::{
+    Callback, FlushPolicy, HandlerFn, Input, Node, Output, PeriodicFn, Replies, RetryPolicy,
+    RpcRequest, TimeoutFn,
+};
+pub use sync::lock;
+pub use txn::TxnOp;
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
@@ -0,0 +1,56 @@
+use std::env;
+use std::fmt;
+
+/// Verbosity of [`crate::Node`]'s stderr log, set with `MAELSTROM_LOG`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    /// Reads `MAELSTROM_LOG` (`debug`, `info`, `warn` or `error`). Defaults
+    /// to `info`: debug logs every message sent, so it has to be asked for.
+    pub fn from_env() -> Self {
+        env::var("MAELSTROM_LOG")
+            .ok()
+            .and_then(|level| LogLevel::parse(&level))
+            .unwrap_or(LogLevel::Info)
+    }
+
+    pub fn parse(level: &str) -> Option<Self> {
+        match level.to_ascii_lowercase().as_str() {
+            "debug" => Some(LogLevel::Debug),
+            "info" => Some(LogLevel::Info),
+            "warn" => Some(LogLevel::Warn),
+            "error" => Some(LogLevel::Error),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Info => "INFO",
+            LogLevel::Warn => "WARN",
+            LogLevel::Error => "ERROR",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_parse_case_insensitively_and_order_by_severity() {
+        assert_eq!(LogLevel::parse("WARN"), Some(LogLevel::Warn));
+        assert_eq!(LogLevel::parse("verbose"), None);
+        assert!(LogLevel::Debug < LogLevel::Info);
+        assert!(LogLevel::Warn < LogLevel::Error);
+    }
+}
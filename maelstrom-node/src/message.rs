@@ -0,0 +1,165 @@
+use crate::error::{ErrorBody, MaelstromError};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+pub type MsgId = u64;
+
+/// The wire format version this crate speaks. A harness that needs another
+/// one says so with `protocol_version` in `init`; older harnesses leave it
+/// out and get this one.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// A node or client id as Maelstrom sends it on the wire, e.g. `"n1"` or `"c3"`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(transparent)]
+pub struct NodeId(String);
+
+impl NodeId {
+    pub fn new(id: impl Into<String>) -> Self {
+        NodeId(id.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Parses the numeric suffix, e.g. `3` for `"n3"`. Returns `None` for
+    /// ids without one, such as the `"seq-kv"` service.
+    pub fn index(&self) -> Option<u64> {
+        self.0
+            .trim_start_matches(|c: char| c.is_ascii_alphabetic())
+            .parse()
+            .ok()
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<&str> for NodeId {
+    fn from(id: &str) -> Self {
+        NodeId::new(id)
+    }
+}
+
+impl From<String> for NodeId {
+    fn from(id: String) -> Self {
+        NodeId(id)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Message<B> {
+    pub src: NodeId,
+    pub dest: NodeId,
+    /// The sender's Lamport time when it wrote this message, if it stamps
+    /// its messages, see [`Node::set_lamport_stamps`](crate::Node::set_lamport_stamps).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lamport: Option<u64>,
+    pub body: B,
+}
+
+/// Protocol fields every challenge body has to expose so the node can
+/// correlate requests and replies.
+pub trait Body: Serialize + DeserializeOwned {
+    /// The body's serde `type` tag, which is how Maelstrom names message
+    /// types, e.g. `"echo_ok"`. Handlers are looked up by it, so it has to
+    /// match the tag the body is written with.
+    fn type_tag(&self) -> &'static str;
+    fn msg_id(&self) -> Option<MsgId>;
+    fn in_reply_to(&self) -> Option<MsgId>;
+
+    /// The code and text of an `error` reply, `None` for any other body.
+    /// The default goes through the error's JSON; override it with a plain
+    /// match on the body's error variant where that matters.
+    fn error(&self) -> Option<MaelstromError> {
+        if self.type_tag() != "error" {
+            return None;
+        }
+        let json = serde_json::to_value(self).ok()?;
+        serde_json::from_value::<ErrorBody>(json)
+            .ok()
+            .map(Into::into)
+    }
+}
+
+/// Bodies whose requests are acknowledged with an `*_ok` that carries
+/// nothing but `in_reply_to`, see [`Node::ack`](crate::Node::ack).
+/// Implement it with [`impl_ack!`](crate::impl_ack).
+pub trait Ack: Body {
+    /// The `*_ok` for this request, or `None` if it has no such reply.
+    fn ack(&self, in_reply_to: MsgId) -> Option<Self>;
+}
+
+/// Implements [`Ack`] by pairing request variants with their `*_ok`:
+///
+/// ```ignore
+/// impl_ack!(MessageBody {
+///     Topology => TopologyOk,
+///     Broadcast => BroadcastOk,
+/// });
+/// ```
+///
+/// An `*_ok` variant with fields besides `in_reply_to` doesn't compile;
+/// requests that need such a reply, or none, are left out and acked with
+/// `None`.
+#[macro_export]
+macro_rules! impl_ack {
+    ($body:ty { $($request:ident => $ok:ident),* $(,)? }) => {
+        impl $crate::Ack for $body {
+            fn ack(&self, in_reply_to: $crate::MsgId) -> Option<Self> {
+                #[allow(unreachable_patterns)]
+                match self {
+                    $(Self::$request { .. } => Some(Self::$ok { in_reply_to }),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+/// The init handshake, which is the same for every challenge.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+pub enum InitBody {
+    #[serde(rename = "init")]
+    Init {
+        msg_id: MsgId,
+        node_id: NodeId,
+        node_ids: Vec<NodeId>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        protocol_version: Option<u32>,
+    },
+    // Carries a msg_id like every other message the node sends, so a
+    // harness can correlate it; the first id the node hands out
+    #[serde(rename = "init_ok")]
+    InitOk { msg_id: MsgId, in_reply_to: MsgId },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_id_index_parses_numeric_suffix() {
+        assert_eq!(NodeId::from("n1").index(), Some(1));
+        assert_eq!(NodeId::from("c3").index(), Some(3));
+        assert_eq!(NodeId::from("n12").index(), Some(12));
+        assert_eq!(NodeId::from("seq-kv").index(), None);
+        assert_eq!(NodeId::from("n").index(), None);
+    }
+
+    #[test]
+    fn node_ids_round_trip_through_serde() {
+        let json =
+            r#"{"src":"c3","dest":"n1","body":{"type":"init_ok","msg_id":1,"in_reply_to":1}}"#;
+        let message: Message<InitBody> = serde_json::from_str(json).unwrap();
+        assert_eq!(message.src, NodeId::from("c3"));
+        assert_eq!(message.dest, NodeId::from("n1"));
+        assert_eq!(serde_json::to_string(&message).unwrap(), json);
+    }
+}
@@ -0,0 +1,2794 @@
+use crate::error::{ErrorBody, ErrorCode, MaelstromError, NodeError, ReceiveError};
+use crate::id::{IdGenerator, Monotonic};
+use crate::log::LogLevel;
+use crate::message::{Ack, Body, InitBody, Message, MsgId, NodeId, PROTOCOL_VERSION};
+use crate::outbound::Outbound;
+use crate::sync::lock;
+use crate::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::borrow::Borrow;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Cursor, LineWriter, Read, Write};
+use std::net::TcpStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often the background sweeper checks for timed out RPCs.
+const RPC_SWEEP_INTERVAL: Duration = Duration::from_millis(10);
+/// How many requests [`Node::is_duplicate`] remembers unless changed with
+/// [`Node::set_dedup_capacity`].
+const DEFAULT_DEDUP_CAPACITY: usize = 4096;
+/// How many bytes a single message read from stdin may take up, unless
+/// changed with MAELSTROM_MAX_LINE or [`Node::set_max_line_length`].
+const DEFAULT_MAX_LINE_LENGTH: usize = 16 * 1024 * 1024;
+/// Messages written that are larger than this many bytes are logged as a
+/// warning, unless changed with MAELSTROM_LARGE_MESSAGE or
+/// [`Node::set_large_message_threshold`].
+const DEFAULT_LARGE_MESSAGE: usize = 64 * 1024;
+// Messages `try_send` queues for its sender thread before it has to wait
+const TRY_SEND_QUEUE: usize = 1024;
+
+/// Invoked with the reply to a request sent through [`Node::rpc`], or with
+/// the code and text of an `error` sent back instead.
+pub type Callback<S, B> = Box<
+    dyn FnOnce(&Arc<Node<S, B>>, std::result::Result<&Message<B>, MaelstromError>) -> Result<()>
+        + Send
+        + 'static,
+>;
+
+/// Invoked when a request sent through [`Node::rpc_with_timeout`] ran out of retries.
+pub type TimeoutFn<S, B> = Box<dyn FnOnce(&Arc<Node<S, B>>) + Send + 'static>;
+
+/// A request for [`Node::rpc_all_with_timeout`]: where it goes, its body
+/// with the `msg_id` already set, and what to do with the reply or timeout.
+pub type RpcRequest<S, B> = (NodeId, B, Callback<S, B>, TimeoutFn<S, B>);
+
+/// Messages a handler registered with [`Node::register_replying`] wants
+/// sent, each body to its destination.
+pub type Replies<B> = Vec<(NodeId, B)>;
+
+/// Handles requests of one message type, see [`Node::register`].
+pub type HandlerFn<S, B> =
+    Arc<dyn Fn(&Arc<Node<S, B>>, &Message<B>) -> Result<()> + Send + Sync + 'static>;
+
+/// Where a node reads its messages from; stdin outside of tests.
+pub type Input = Box<dyn BufRead + Send>;
+
+/// Where a node writes its messages to; stdout outside of tests.
+pub type Output = Box<dyn Write + Send>;
+
+/// The file MAELSTROM_RECORD names, shared by a node's input and output so
+/// lines land in the order they were read and written.
+type Recording = Arc<Mutex<LineWriter<File>>>;
+
+/// Periodic work that does not correspond to an incoming message, e.g. gossip.
+pub type PeriodicFn<S, B> = Box<dyn Fn(&Arc<Node<S, B>>) + Send + 'static>;
+
+/// How long to wait for a reply and how often to resend before giving up.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub timeout: Duration,
+    pub max_retries: u32,
+}
+
+/// When messages buffered for stdout are written out, set with
+/// [`Node::set_flush_policy`] or MAELSTROM_FLUSH. Fewer flushes mean fewer
+/// write syscalls and more throughput, but messages, replies included, sit
+/// in the buffer for longer. Output from [`Node::with_ordered_output`] is
+/// flushed by its sender thread and ignores the policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// On every [`Node::flush`], i.e. once a batch of messages is handled.
+    /// The lowest latency, and the default. `each` in MAELSTROM_FLUSH.
+    Each,
+    /// Once this many messages were written since the last flush. A reply
+    /// waits until enough others follow it, for as long as traffic keeps
+    /// coming; only use it under steady load. `batch:N`.
+    Batch(usize),
+    /// From a background thread at this interval, so a message waits at
+    /// most about that long. `time:Nms`.
+    Timed(Duration),
+}
+
+impl FlushPolicy {
+    /// Parses `each`, `batch:N` or `time:Nms`, with N at least 1.
+    pub fn parse(policy: &str) -> Option<Self> {
+        let count = |n: &str| n.parse().ok().filter(|n| *n > 0);
+        match policy.split_once(':') {
+            None if policy == "each" => Some(FlushPolicy::Each),
+            Some(("batch", n)) => count(n).map(FlushPolicy::Batch),
+            Some(("time", ms)) => count(ms.strip_suffix("ms")?)
+                .map(|ms| FlushPolicy::Timed(Duration::from_millis(ms as u64))),
+            _ => None,
+        }
+    }
+}
+
+struct PendingRpc<S, B> {
+    callback: Callback<S, B>,
+    timeout: Option<RpcTimeout<S, B>>,
+}
+
+struct RpcTimeout<S, B> {
+    dest: NodeId,
+    // The serialized request, resent verbatim so the reply keeps its `in_reply_to`
+    line: String,
+    policy: RetryPolicy,
+    attempts: u32,
+    deadline: Instant,
+    on_timeout: TimeoutFn<S, B>,
+}
+
+pub struct Node<S, B> {
+    pub node_id: NodeId,
+    /// Every node in the cluster as sent in `init`, including this one. Use
+    /// [`Node::peers`] for the others.
+    pub node_ids: Vec<NodeId>,
+    /// The wire format version agreed on in `init`, [`PROTOCOL_VERSION`]
+    /// unless the harness asked for another supported one.
+    pub protocol_version: u32,
+    pub state: S,
+    // The LogLevel, as u8 so it can be changed after init
+    log_level: AtomicU8,
+    msg_ids: Monotonic,
+    shutdown: AtomicBool,
+    // Messages read by `receive` and lines written to stdout, resends included
+    received: AtomicU64,
+    sent: AtomicU64,
+    // Bytes written per body `type`, newlines and resends included
+    bytes_sent: Mutex<HashMap<String, u64>>,
+    large_message: AtomicUsize,
+    // Messages `dispatch` is done with, and the signal that it finished one
+    dispatched: Mutex<u64>,
+    idle: Condvar,
+    // Ticked on every message written, raised past the sender's time on
+    // every stamped message read
+    lamport: AtomicU64,
+    stamp_lamport: AtomicBool,
+    flush_policy: Mutex<FlushPolicy>,
+    // Lines written to stdout since it was last flushed. Only changed with
+    // `stdout` locked.
+    unflushed: AtomicUsize,
+    stdout: Arc<Mutex<BufWriter<Output>>>,
+    // Set by `with_ordered_output`; lines then go through its queues
+    outbound: OnceLock<Arc<Outbound>>,
+    // Started by the first `try_send` without ordered output
+    try_outbound: OnceLock<Arc<Outbound>>,
+    stderr: Arc<Mutex<io::Stderr>>,
+    stdin: Arc<Mutex<Frames<Input>>>,
+    recording: Option<Recording>,
+    callbacks: Arc<Mutex<HashMap<MsgId, PendingRpc<S, B>>>>,
+    // How many entries `callbacks` may hold before new RPCs wait, and the
+    // signal that a reply or timeout removed one
+    max_in_flight: AtomicUsize,
+    rpc_done: Condvar,
+    // Keyed by the body's serde `type` tag
+    handlers: Arc<Mutex<HashMap<String, HandlerFn<S, B>>>>,
+    seen: Mutex<SeenRequests>,
+}
+
+/// The most recent requests by `(src, msg_id)`, oldest first.
+struct SeenRequests {
+    order: VecDeque<(NodeId, MsgId)>,
+    ids: HashSet<(NodeId, MsgId)>,
+    capacity: usize,
+}
+
+impl SeenRequests {
+    fn evict_to_capacity(&mut self) {
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+    }
+}
+
+impl<S, B: Body> Node<S, B> {
+    pub fn new(node_id: &NodeId, node_ids: Vec<NodeId>, state: S) -> Arc<Self> {
+        Node::with_io(
+            node_id,
+            node_ids,
+            state,
+            Box::new(BufReader::new(io::stdin())),
+            Box::new(io::stdout()),
+        )
+    }
+
+    /// Like [`Node::new`], but talks over `input` and `output` instead of
+    /// stdin and stdout. Logs still go to stderr.
+    pub fn with_io(
+        node_id: &NodeId,
+        node_ids: Vec<NodeId>,
+        state: S,
+        input: Input,
+        output: Output,
+    ) -> Arc<Self> {
+        Node::with_frames(
+            node_id,
+            node_ids,
+            PROTOCOL_VERSION,
+            state,
+            Frames::new(input),
+            output,
+        )
+    }
+
+    fn with_frames(
+        node_id: &NodeId,
+        node_ids: Vec<NodeId>,
+        protocol_version: u32,
+        state: S,
+        input: Frames<Input>,
+        output: Output,
+    ) -> Arc<Self> {
+        let recording = input.recording.clone();
+        Arc::new(Node {
+            node_id: node_id.clone(),
+            node_ids,
+            protocol_version,
+            state,
+            log_level: AtomicU8::new(LogLevel::from_env() as u8),
+            msg_ids: Monotonic::new(),
+            shutdown: AtomicBool::new(false),
+            received: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            bytes_sent: Mutex::new(HashMap::new()),
+            large_message: AtomicUsize::new(DEFAULT_LARGE_MESSAGE),
+            dispatched: Mutex::new(0),
+            idle: Condvar::new(),
+            lamport: AtomicU64::new(0),
+            stamp_lamport: AtomicBool::new(false),
+            flush_policy: Mutex::new(FlushPolicy::Each),
+            unflushed: AtomicUsize::new(0),
+            stdout: Arc::new(Mutex::new(BufWriter::new(output))),
+            outbound: OnceLock::new(),
+            try_outbound: OnceLock::new(),
+            stderr: Arc::new(Mutex::new(io::stderr())),
+            stdin: Arc::new(Mutex::new(input)),
+            recording,
+            callbacks: Arc::new(Mutex::new(HashMap::new())),
+            max_in_flight: AtomicUsize::new(usize::MAX),
+            rpc_done: Condvar::new(),
+            handlers: Arc::new(Mutex::new(HashMap::new())),
+            seen: Mutex::new(SeenRequests {
+                order: VecDeque::new(),
+                ids: HashSet::new(),
+                capacity: DEFAULT_DEDUP_CAPACITY,
+            }),
+        })
+    }
+
+    /// Reads the `init` message, acknowledges it and returns the node with
+    /// its RPC timeout sweeper running. An `init` asking for a protocol
+    /// version other than [`PROTOCOL_VERSION`] is answered with a
+    /// `not-supported` error and fails initialization.
+    ///
+    /// With MAELSTROM_OUTPUT set to `tcp://host:port`, messages are written
+    /// to that address as newline-delimited JSON instead of to stdout, so a
+    /// custom client can drive the node outside of Maelstrom.
+    ///
+    /// Init is read on the calling thread. Start reader, worker and
+    /// periodic threads only once this returned, so none of them reads the
+    /// input before init or uses a node that isn't acknowledged yet.
+    pub fn init(state: S) -> Result<Arc<Self>>
+    where
+        S: Send + Sync + 'static,
+        B: Send + 'static,
+    {
+        let output = output_to(std::env::var("MAELSTROM_OUTPUT").ok())?;
+        Node::init_with_io(state, Box::new(BufReader::new(io::stdin())), output)
+    }
+
+    /// Like [`Node::init`], but reads `init` from and keeps talking over
+    /// `input` and `output`.
+    ///
+    /// With MAELSTROM_RECORD set to a path, every message read and written,
+    /// `init` included, is also appended to that file as JSON lines, which
+    /// [`Node::replay`] can run again later.
+    pub fn init_with_io(state: S, input: Input, output: Output) -> Result<Arc<Self>>
+    where
+        S: Send + Sync + 'static,
+        B: Send + 'static,
+    {
+        let mut input = Frames::new(input);
+        if let Ok(configured) = std::env::var("MAELSTROM_MAX_LINE") {
+            input.max_line = match configured.parse() {
+                Ok(0) | Err(_) => {
+                    return Err(format!("Invalid MAELSTROM_MAX_LINE '{}'", configured).into())
+                }
+                Ok(max_line) => max_line,
+            };
+        }
+        let max_in_flight = match std::env::var("MAELSTROM_MAX_IN_FLIGHT") {
+            Ok(configured) => match configured.parse() {
+                Ok(0) | Err(_) => {
+                    return Err(format!("Invalid MAELSTROM_MAX_IN_FLIGHT '{}'", configured).into())
+                }
+                Ok(max_in_flight) => Some(max_in_flight),
+            },
+            Err(_) => None,
+        };
+        let stamp_lamport = match std::env::var("MAELSTROM_LAMPORT").as_deref() {
+            Ok("1") => true,
+            Ok("0") | Err(_) => false,
+            Ok(configured) => {
+                return Err(format!("Invalid MAELSTROM_LAMPORT '{}'", configured).into())
+            }
+        };
+        let large_message = match std::env::var("MAELSTROM_LARGE_MESSAGE") {
+            Ok(configured) => match configured.parse() {
+                Ok(0) | Err(_) => {
+                    return Err(format!("Invalid MAELSTROM_LARGE_MESSAGE '{}'", configured).into())
+                }
+                Ok(bytes) => Some(bytes),
+            },
+            Err(_) => None,
+        };
+        let flush_policy = match std::env::var("MAELSTROM_FLUSH") {
+            Ok(configured) => match FlushPolicy::parse(&configured) {
+                Some(policy) => Some(policy),
+                None => return Err(format!("Invalid MAELSTROM_FLUSH '{}'", configured).into()),
+            },
+            Err(_) => None,
+        };
+        if let Ok(path) = std::env::var("MAELSTROM_RECORD") {
+            let file = File::create(&path)
+                .map_err(|e| format!("Failed to create recording {}: {}", path, e))?;
+            input.recording = Some(Arc::new(Mutex::new(LineWriter::new(file))));
+        }
+        let node = Node::init_from_frames(state, input, output)?;
+        if let Some(max_in_flight) = max_in_flight {
+            node.set_max_in_flight(max_in_flight);
+        }
+        node.set_lamport_stamps(stamp_lamport);
+        if let Some(large_message) = large_message {
+            node.set_large_message_threshold(large_message);
+        }
+        if let Some(flush_policy) = flush_policy {
+            node.set_flush_policy(flush_policy);
+        }
+        Ok(node)
+    }
+
+    /// Feeds the messages a node received in a run recorded with
+    /// MAELSTROM_RECORD to a new node, starting with `init`, and writes its
+    /// messages to `output`. Register the handlers and call [`Node::run`] as
+    /// after [`Node::init`]. There are no real peers: replies to the node's own
+    /// RPCs arrive as recorded, so a deterministic challenge writes the
+    /// same messages again.
+    pub fn replay(state: S, path: impl AsRef<Path>, output: Output) -> Result<Arc<Self>>
+    where
+        S: Send + Sync + 'static,
+        B: Send + 'static,
+    {
+        let recorded = std::fs::read_to_string(path)?;
+        // The first line is init, whose destination is the recorded node.
+        // Everything else it received was sent to it as well, and nothing
+        // it wrote was.
+        let mut node_id = None;
+        let mut received = String::new();
+        for line in recorded.lines() {
+            let message: Message<serde::de::IgnoredAny> = serde_json::from_str(line)?;
+            if *node_id.get_or_insert_with(|| message.dest.clone()) == message.dest {
+                received.push_str(line);
+                received.push('\n');
+            }
+        }
+        Node::init_from_frames(
+            state,
+            Frames::new(Box::new(Cursor::new(received.into_bytes()))),
+            output,
+        )
+    }
+
+    fn init_from_frames(state: S, mut input: Frames<Input>, output: Output) -> Result<Arc<Self>>
+    where
+        S: Send + Sync + 'static,
+        B: Send + 'static,
+    {
+        // Anything that arrived along with init stays buffered for the node
+        let message: Message<InitBody> = read_message(&mut input)?;
+        let InitBody::Init {
+            msg_id,
+            node_id,
+            node_ids,
+            protocol_version,
+        } = &message.body
+        else {
+            return Err("First message received must be init".into());
+        };
+        let protocol_version = protocol_version.unwrap_or(PROTOCOL_VERSION);
+        let node = Node::with_frames(
+            node_id,
+            node_ids.clone(),
+            protocol_version,
+            state,
+            input,
+            output,
+        );
+        if protocol_version != PROTOCOL_VERSION {
+            let text = format!(
+                "Protocol version {} is not supported, only {}",
+                protocol_version, PROTOCOL_VERSION
+            );
+            node.write(
+                &message.src,
+                ErrorBody {
+                    in_reply_to: *msg_id,
+                    code: ErrorCode::NotSupported,
+                    text: text.clone(),
+                },
+            )?;
+            node.flush()?;
+            return Err(text.into());
+        }
+        node.log(&format!("Initialized Node: {}", &node.node_id));
+        node.write(
+            &message.src,
+            InitBody::InitOk {
+                msg_id: node.get_next_msg_id(),
+                in_reply_to: *msg_id,
+            },
+        )?;
+        node.flush()?;
+        node.every(
+            RPC_SWEEP_INTERVAL,
+            Box::new(|node| {
+                node.sweep_timeouts();
+            }),
+        );
+        Ok(node)
+    }
+
+    /// Every node in the cluster except this one.
+    pub fn peers(&self) -> impl Iterator<Item = &NodeId> {
+        self.node_ids.iter().filter(move |id| **id != self.node_id)
+    }
+
+    /// A fresh id for a message this node sends, starting at 1. These live in
+    /// their own space: `in_reply_to` only ever refers to ids the node handed
+    /// out itself, never to ids clients or Maelstrom chose.
+    pub fn get_next_msg_id(&self) -> MsgId {
+        self.msg_ids.next_id()
+    }
+
+    /// Spawns a thread that calls `f` every `dt` until the node shuts down.
+    pub fn every(self: &Arc<Self>, dt: Duration, f: PeriodicFn<S, B>) -> thread::JoinHandle<()>
+    where
+        S: Send + Sync + 'static,
+        B: Send + 'static,
+    {
+        let node = Arc::clone(self);
+        thread::spawn(move || loop {
+            thread::sleep(dt);
+            if node.is_shutdown() {
+                break;
+            }
+            f(&node);
+        })
+    }
+
+    /// Stops periodic tasks started with [`Node::every`].
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Records `request` and returns whether the same `(src, msg_id)` was
+    /// seen before, e.g. redelivered after a partition healed. A handler with
+    /// side effects can then re-ack without applying them again. Only the
+    /// most recent requests are remembered, see [`Node::set_dedup_capacity`].
+    /// Messages without a `msg_id` are never duplicates.
+    pub fn is_duplicate(&self, request: &Message<B>) -> bool {
+        let Some(msg_id) = request.body.msg_id() else {
+            return false;
+        };
+        let id = (request.src.clone(), msg_id);
+        let mut seen = lock(&self.seen);
+        if !seen.ids.insert(id.clone()) {
+            return true;
+        }
+        seen.order.push_back(id);
+        seen.evict_to_capacity();
+        false
+    }
+
+    /// Sets how many requests [`Node::is_duplicate`] remembers, forgetting
+    /// the oldest ones beyond that.
+    pub fn set_dedup_capacity(&self, capacity: usize) {
+        let mut seen = lock(&self.seen);
+        seen.capacity = capacity;
+        seen.evict_to_capacity();
+    }
+
+    /// How many messages [`Node::receive`] has returned. The `init` message
+    /// is not counted.
+    pub fn received_count(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// How many messages this node has written, RPC resends included.
+    pub fn sent_count(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// Bytes written per body `type`, e.g. `"gossip"`, counting each line's
+    /// newline and every RPC resend. Shows which messages use up the
+    /// network, such as gossip that carries whole sets.
+    pub fn bytes_sent_by_type(&self) -> BTreeMap<String, u64> {
+        lock(&self.bytes_sent)
+            .iter()
+            .map(|(type_tag, bytes)| (type_tag.clone(), *bytes))
+            .collect()
+    }
+
+    /// Logs a warning for every message written that is larger than
+    /// `bytes`. 64 KiB by default, or MAELSTROM_LARGE_MESSAGE for nodes
+    /// created with [`Node::init`].
+    pub fn set_large_message_threshold(&self, bytes: usize) {
+        self.large_message.store(bytes, Ordering::Relaxed);
+    }
+
+    /// The node's Lamport clock. It ticks on every message written and every
+    /// message [`Node::receive`] reads, and a stamped message moves it past
+    /// the sender's time. If sending one message led to sending another, on
+    /// this node or a peer that stamps, the second carries the later time.
+    pub fn lamport_time(&self) -> u64 {
+        self.lamport.load(Ordering::SeqCst)
+    }
+
+    /// Writes the Lamport time into the `lamport` field of every message
+    /// sent from now on. Off by default, or MAELSTROM_LAMPORT=1 for nodes
+    /// created with [`Node::init`]: the clock keeps counting either way,
+    /// but only nodes that stamp carry it over to their peers, and Maelstrom
+    /// only documents `src`, `dest` and `body` in the envelope.
+    pub fn set_lamport_stamps(&self, on: bool) {
+        self.stamp_lamport.store(on, Ordering::Relaxed);
+    }
+
+    /// Decides when buffered messages are written out, see [`FlushPolicy`].
+    /// [`FlushPolicy::Each`] by default, or MAELSTROM_FLUSH for nodes
+    /// created with [`Node::init`]. A timed policy starts its flushing
+    /// thread here, so set one at most once.
+    ///
+    /// Once the node is shut down, every [`Node::flush`] writes everything
+    /// out again, so nothing is left behind when the input ends.
+    pub fn set_flush_policy(self: &Arc<Self>, policy: FlushPolicy)
+    where
+        S: Send + Sync + 'static,
+        B: Send + 'static,
+    {
+        *lock(&self.flush_policy) = policy;
+        if let FlushPolicy::Timed(interval) = policy {
+            self.every(
+                interval,
+                Box::new(|node| {
+                    let mut stdout = lock(&node.stdout);
+                    if let Err(e) = stdout.flush() {
+                        node.log_error(&format!("Failed to flush stdout: {}", e));
+                    }
+                    node.unflushed.store(0, Ordering::Relaxed);
+                }),
+            );
+        }
+    }
+
+    /// Whether stdout is to be flushed with `unflushed` lines in it, when
+    /// the caller asked for it if `requested`.
+    fn flush_due(&self, unflushed: usize, requested: bool) -> bool {
+        if requested && self.is_shutdown() {
+            return true;
+        }
+        match *lock(&self.flush_policy) {
+            FlushPolicy::Each => requested,
+            FlushPolicy::Batch(count) => unflushed >= count,
+            FlushPolicy::Timed(_) => false,
+        }
+    }
+
+    /// Caps how many RPCs may wait for a reply at once. Once `count` are
+    /// pending, [`Node::rpc`] and friends block until a reply arrives or a
+    /// request times out. Unlimited by default, or MAELSTROM_MAX_IN_FLIGHT
+    /// for nodes created with [`Node::init`].
+    ///
+    /// Handlers and callbacks run on the threads that would free a slot, so
+    /// there a full cap fails with an [`io::Error`] of kind
+    /// [`WouldBlock`](io::ErrorKind::WouldBlock) instead of waiting, and the
+    /// caller can fail the request or try again later.
+    pub fn set_max_in_flight(&self, count: usize) {
+        self.max_in_flight.store(count, Ordering::Relaxed);
+        self.rpc_done.notify_all();
+    }
+
+    /// Locks the pending RPCs once there is room for `count` more. A batch
+    /// larger than the whole cap goes out as soon as nothing else is pending.
+    fn callbacks_with_room(
+        &self,
+        count: usize,
+    ) -> Result<MutexGuard<'_, HashMap<MsgId, PendingRpc<S, B>>>> {
+        let mut callbacks = lock(&self.callbacks);
+        while !callbacks.is_empty()
+            && callbacks.len() + count > self.max_in_flight.load(Ordering::Relaxed)
+        {
+            if DISPATCHING.with(Cell::get) {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!("{} RPCs are already in flight", callbacks.len()),
+                )
+                .into());
+            }
+            callbacks = self
+                .rpc_done
+                .wait(callbacks)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        Ok(callbacks)
+    }
+
+    /// Skips incoming messages longer than `bytes` instead of buffering them,
+    /// see [`ReceiveError::TooLong`]. Defaults to 16 MiB, or MAELSTROM_MAX_LINE
+    /// for nodes created with [`Node::init`].
+    pub fn set_max_line_length(&self, bytes: usize) {
+        lock(&self.stdin).max_line = bytes;
+    }
+
+    /// Reads the next message. Never panics: on [`ReceiveError::Eof`] the node
+    /// is shut down and the caller should stop its receive loop, any other
+    /// error is safe to log and retry.
+    pub fn receive(&self) -> std::result::Result<Message<B>, ReceiveError> {
+        let message = read_message(&mut *lock(&self.stdin));
+        match &message {
+            Ok(message) => {
+                self.received.fetch_add(1, Ordering::SeqCst);
+                let sent_at = message.lamport.unwrap_or(0);
+                let _ = self
+                    .lamport
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |local| {
+                        Some(local.max(sent_at) + 1)
+                    });
+            }
+            Err(ReceiveError::Eof) => self.shutdown(),
+            Err(_) => {}
+        }
+        message
+    }
+
+    /// Routes requests whose body has `"type": type_tag` to `handler` in
+    /// [`Node::dispatch`]. Registering a tag again replaces its handler.
+    pub fn register<F, E>(&self, type_tag: &str, handler: F)
+    where
+        F: Fn(&Arc<Self>, &Message<B>) -> std::result::Result<(), E> + Send + Sync + 'static,
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        let handler: HandlerFn<S, B> =
+            Arc::new(move |node, message| handler(node, message).map_err(Into::into));
+        lock(&self.handlers).insert(type_tag.to_string(), handler);
+    }
+
+    /// Like [`Node::register`], but `handler` returns the messages to send,
+    /// usually a single reply, instead of sending them itself. They are
+    /// written once it returns, without a flush, so a test can check a
+    /// handler by what it returns rather than by capturing output.
+    pub fn register_replying<F, E>(&self, type_tag: &str, handler: F)
+    where
+        F: Fn(&Arc<Self>, &Message<B>) -> std::result::Result<Replies<B>, E>
+            + Send
+            + Sync
+            + 'static,
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        self.register(type_tag, move |node: &Arc<Self>, message: &Message<B>| {
+            let replies = handler(node, message).map_err(Into::into)?;
+            node.write_all(&replies, false)
+        });
+    }
+
+    /// Fires the callback for a reply, or runs the handler registered for the
+    /// request's type through [`Node::handle`] and logs its error. Does not
+    /// flush.
+    pub fn dispatch(self: &Arc<Self>, message: &Message<B>) {
+        self.route(message);
+        *lock(&self.dispatched) += 1;
+        self.idle.notify_all();
+    }
+
+    /// Blocks until every message [`Node::receive`] returned so far has been
+    /// through [`Node::dispatch`], so a test can check state once the
+    /// workers are done instead of sleeping. Returns `false` if that took
+    /// longer than `timeout`.
+    ///
+    /// Input the reader hasn't got to yet isn't waited for, so let it reach
+    /// the end of its input first. Messages passed to `dispatch` without
+    /// `receive`, as [`crate::testing::Network`] does, make the node look
+    /// idle early.
+    pub fn await_quiescence(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut dispatched = lock(&self.dispatched);
+        while *dispatched < self.received.load(Ordering::SeqCst) {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return false;
+            }
+            dispatched = self
+                .idle
+                .wait_timeout(dispatched, left)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        true
+    }
+
+    fn route(self: &Arc<Self>, message: &Message<B>) {
+        // Init is read before dispatching starts, so it never gets here
+        if message.dest != self.node_id {
+            self.log_warn(&format!(
+                "Dropping message from {} addressed to {}",
+                message.src, message.dest
+            ));
+            return;
+        }
+        if self.handle_reply(message) {
+            return;
+        }
+        if let Err(e) = self.handle(message) {
+            self.log_error(&e.to_string());
+        }
+    }
+
+    /// Runs the handler registered for the request's type, turning a panic
+    /// into an error. Requests nobody registered for are answered with a
+    /// `not-supported` error. Does not flush.
+    pub fn handle(self: &Arc<Self>, message: &Message<B>) -> Result<()> {
+        let type_tag = message.body.type_tag();
+        let handler = lock(&self.handlers).get(type_tag).cloned();
+        let _dispatching = Dispatching::enter();
+        match handler {
+            // A panicking handler must not take the calling worker down with it
+            Some(handler) => match panic::catch_unwind(AssertUnwindSafe(|| handler(self, message)))
+            {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(e)) => Err(format!("Failed to handle {}: {}", type_tag, e).into()),
+                Err(_) => Err(format!("Handler for {} panicked", type_tag).into()),
+            },
+            None => {
+                let text = format!("No handler for message type {:?}", type_tag);
+                self.reply_error(message, ErrorCode::NotSupported, &text)
+                    .map_err(|e| format!("{}: {}", text, e).into())
+            }
+        }
+    }
+
+    /// Dispatches messages one at a time, flushing after each, until stdin is
+    /// closed or the node is shut down.
+    ///
+    /// Everything happens on the calling thread in arrival order, so replies
+    /// come out in a deterministic order. Binaries with a worker pool call
+    /// [`Node::dispatch`] from their workers instead, and test their handlers
+    /// through this loop, see [`crate::testing::run_lines`].
+    pub fn run(self: &Arc<Self>) {
+        loop {
+            match self.receive() {
+                Ok(message) => self.dispatch(&message),
+                Err(ReceiveError::Eof) => {
+                    // Shut down by now, so this writes out what the flush
+                    // policy held back
+                    if let Err(e) = self.flush() {
+                        self.log_error(&format!("Failed to flush stdout: {}", e));
+                    }
+                    return;
+                }
+                Err(e) => self.log_warn(&format!("Failed to receive message: {}", e)),
+            }
+            if let Err(e) = self.flush() {
+                self.log_error(&format!("Failed to flush stdout: {}", e));
+            }
+            if self.is_shutdown() {
+                return;
+            }
+        }
+    }
+
+    /// Queues a message on the buffered stdout. Call [`Node::flush`] once a
+    /// batch of messages has been handled so Maelstrom actually sees them.
+    /// Fails with [`NodeError::Serialize`] if `body` can't be serialized,
+    /// leaving the node running.
+    pub fn send(&self, dest: &NodeId, body: B) -> Result<()> {
+        self.write(dest, body)
+    }
+
+    /// Like [`Node::send`], but gives up instead of blocking for longer than
+    /// `timeout`, e.g. while stdout is a full pipe nobody reads. The message
+    /// is queued for a sender thread, which writes and flushes it; a full
+    /// queue fails with an [`io::Error`] of kind
+    /// [`WouldBlock`](io::ErrorKind::WouldBlock), and the caller can drop
+    /// the message or send it again later. Write errors surface in the next
+    /// [`Node::flush`].
+    ///
+    /// Messages to one destination keep their order, but without
+    /// [`Node::with_ordered_output`] they may be written before or after
+    /// messages sent with [`Node::send`] around the same time.
+    pub fn try_send(&self, dest: &NodeId, body: B, timeout: Duration) -> Result<()> {
+        let line = self.serialize(dest, body)?;
+        let lines = [(dest, line.as_str())];
+        self.check_protocol(&lines);
+        let outbound = match self.outbound.get() {
+            Some(outbound) => outbound,
+            None => self
+                .try_outbound
+                .get_or_init(|| Outbound::start(Arc::clone(&self.stdout), TRY_SEND_QUEUE)),
+        };
+        outbound.push_within(dest, &line, timeout)?;
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        self.wrote(&lines);
+        Ok(())
+    }
+
+    /// Writes all `messages` back to back under a single stdout lock and
+    /// flushes once, so fanning out to many nodes doesn't take the lock per
+    /// message. Each message is still a line of its own.
+    pub fn send_all(&self, messages: &[(NodeId, B)]) -> Result<()> {
+        self.write_all(messages, true)
+    }
+
+    fn write_all(&self, messages: &[(NodeId, B)], flush: bool) -> Result<()> {
+        let lines: Vec<(&NodeId, String)> = messages
+            .iter()
+            .map(|(dest, body)| Ok((dest, self.serialize(dest, body)?)))
+            .collect::<Result<_>>()?;
+        self.write_lines(&lines, flush)
+    }
+
+    /// Sends every peer its own copy of the body built by `make_body`, which
+    /// is given a fresh `msg_id` for each, see [`Node::send_all`]. Returns how
+    /// many copies were sent: 0 for a node alone in its cluster, and for one
+    /// whose stdout failed, which is logged.
+    pub fn broadcast_to_peers(&self, make_body: impl Fn(MsgId) -> B) -> usize {
+        let messages: Vec<(NodeId, B)> = self
+            .peers()
+            .map(|peer| (peer.clone(), make_body(self.get_next_msg_id())))
+            .collect();
+        match self.send_all(&messages) {
+            Ok(()) => messages.len(),
+            Err(e) => {
+                self.log_error(&format!("Failed to send to peers: {}", e));
+                0
+            }
+        }
+    }
+
+    /// Sends the body built by `make_body` back to the sender of `request`.
+    /// `make_body` is given the request's `msg_id` to use as `in_reply_to`.
+    ///
+    /// Replies always go to `request.src`. A request without a `msg_id`, such
+    /// as an ack, cannot be replied to and is rejected with an error.
+    pub fn reply(&self, request: &Message<B>, make_body: impl FnOnce(MsgId) -> B) -> Result<()> {
+        let in_reply_to = reply_id(request)?;
+        self.write(&request.src, make_body(in_reply_to))
+    }
+
+    /// Replies to `request` with its `*_ok` from [`Ack`]. Fails for requests
+    /// that have none.
+    pub fn ack(&self, request: &Message<B>) -> Result<()>
+    where
+        B: Ack,
+    {
+        let in_reply_to = reply_id(request)?;
+        let body = request.body.ack(in_reply_to).ok_or_else(|| {
+            format!(
+                "Message from {} has no *_ok to acknowledge it with",
+                request.src
+            )
+        })?;
+        self.write(&request.src, body)
+    }
+
+    /// Answers `request` with a Maelstrom `error` instead of its `*_ok`.
+    pub fn reply_error(&self, request: &Message<B>, code: ErrorCode, text: &str) -> Result<()> {
+        let in_reply_to = reply_id(request)?;
+        self.write(
+            &request.src,
+            ErrorBody {
+                in_reply_to,
+                code,
+                text: text.to_string(),
+            },
+        )
+    }
+
+    /// Writes out everything sent so far, or leaves it buffered if the
+    /// [`FlushPolicy`] says so. With [`Node::with_ordered_output`], or after
+    /// [`Node::try_send`], this waits for the sender thread to drain the
+    /// queues.
+    pub fn flush(&self) -> Result<()> {
+        for outbound in self.outbound_queues() {
+            outbound.wait_drained()?;
+        }
+        let mut stdout = lock(&self.stdout);
+        if self.flush_due(self.unflushed.load(Ordering::Relaxed), true) {
+            stdout.flush()?;
+            self.unflushed.store(0, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Routes every message through a queue per destination, drained by a
+    /// dedicated sender thread, so messages to one node are written in the
+    /// order they were sent even when several workers send to it. Sends
+    /// return once the message is queued; [`Node::flush`] waits until the
+    /// queues are written. Call it right after initialization, before
+    /// anything else is sent.
+    pub fn with_ordered_output(self: Arc<Self>) -> Arc<Self> {
+        self.outbound
+            .get_or_init(|| Outbound::start(Arc::clone(&self.stdout), usize::MAX));
+        self
+    }
+
+    fn outbound_queues(&self) -> impl Iterator<Item = &Arc<Outbound>> {
+        self.outbound
+            .get()
+            .into_iter()
+            .chain(self.try_outbound.get())
+    }
+
+    /// Shuts the node down, flushes stdout and exits the process. Stdout
+    /// stays locked until the process is gone, so no other thread can start
+    /// a message that would be cut off half-written. Meant for signal
+    /// handlers.
+    pub fn exit(&self, code: i32) -> ! {
+        self.shutdown();
+        for outbound in self.outbound_queues() {
+            if let Err(e) = outbound.wait_drained() {
+                self.log_error(&format!("Failed to write queued messages on exit: {}", e));
+            }
+        }
+        let mut stdout = lock(&self.stdout);
+        if let Err(e) = stdout.flush() {
+            self.log_error(&format!("Failed to flush stdout on exit: {}", e));
+        }
+        std::process::exit(code)
+    }
+
+    /// Sends a request with a fresh `msg_id` and registers `response_handler`
+    /// to run once the matching reply arrives. Waits first while
+    /// [`Node::set_max_in_flight`] RPCs are pending, or fails with
+    /// `WouldBlock` when called from a handler or callback.
+    pub fn rpc(
+        &self,
+        dest: &NodeId,
+        make_body: impl FnOnce(MsgId) -> B,
+        response_handler: Callback<S, B>,
+    ) -> Result<MsgId> {
+        let rpc_id = self.get_next_msg_id();
+        let line = self.serialize(dest, make_body(rpc_id))?;
+        {
+            let mut callbacks = self.callbacks_with_room(1)?;
+            let _ = callbacks.insert(
+                rpc_id,
+                PendingRpc {
+                    callback: response_handler,
+                    timeout: None,
+                },
+            );
+        }
+        // Without a timeout nothing else would ever free its slot
+        if let Err(e) = self.write_line(dest, &line) {
+            lock(&self.callbacks).remove(&rpc_id);
+            self.rpc_done.notify_all();
+            return Err(e);
+        }
+        Ok(rpc_id)
+    }
+
+    /// Like [`Node::rpc`], but resends the request up to `policy.max_retries`
+    /// times if no reply arrives within `policy.timeout`, then gives up and
+    /// calls `on_timeout`.
+    pub fn rpc_with_timeout(
+        &self,
+        dest: &NodeId,
+        make_body: impl FnOnce(MsgId) -> B,
+        policy: RetryPolicy,
+        response_handler: Callback<S, B>,
+        on_timeout: TimeoutFn<S, B>,
+    ) -> Result<MsgId> {
+        let rpc_id = self.get_next_msg_id();
+        let line = self.serialize(dest, make_body(rpc_id))?;
+        {
+            let mut callbacks = self.callbacks_with_room(1)?;
+            let _ = callbacks.insert(
+                rpc_id,
+                PendingRpc {
+                    callback: response_handler,
+                    timeout: Some(RpcTimeout {
+                        dest: dest.clone(),
+                        line: line.clone(),
+                        policy,
+                        attempts: 0,
+                        deadline: Instant::now() + policy.timeout,
+                        on_timeout,
+                    }),
+                },
+            );
+        }
+        // Otherwise the sweep would resend it after the caller saw it fail
+        if let Err(e) = self.write_line(dest, &line) {
+            lock(&self.callbacks).remove(&rpc_id);
+            self.rpc_done.notify_all();
+            return Err(e);
+        }
+        Ok(rpc_id)
+    }
+
+    /// Like [`Node::rpc_with_timeout`] for several requests at once, written
+    /// with [`Node::send_all`]. Each body must already carry its `msg_id`,
+    /// taken from [`Node::get_next_msg_id`]; a body without one fails before
+    /// anything is sent.
+    pub fn rpc_all_with_timeout(
+        &self,
+        requests: Vec<RpcRequest<S, B>>,
+        policy: RetryPolicy,
+    ) -> Result<()> {
+        let mut pending = Vec::with_capacity(requests.len());
+        for (dest, body, response_handler, on_timeout) in requests {
+            let rpc_id = body
+                .msg_id()
+                .ok_or_else(|| format!("Request to {} has no msg_id", dest))?;
+            let line = self.serialize(&dest, &body)?;
+            pending.push((rpc_id, dest, line, response_handler, on_timeout));
+        }
+        let mut lines = Vec::with_capacity(pending.len());
+        {
+            let mut callbacks = self.callbacks_with_room(pending.len())?;
+            for (rpc_id, dest, line, response_handler, on_timeout) in pending {
+                lines.push((dest.clone(), line.clone()));
+                let _ = callbacks.insert(
+                    rpc_id,
+                    PendingRpc {
+                        callback: response_handler,
+                        timeout: Some(RpcTimeout {
+                            dest,
+                            line,
+                            policy,
+                            attempts: 0,
+                            deadline: Instant::now() + policy.timeout,
+                            on_timeout,
+                        }),
+                    },
+                );
+            }
+        }
+        self.write_lines(&lines, true)
+    }
+
+    /// Resends RPCs past their deadline and expires those out of retries.
+    /// Returns the number of requests resent. Runs in the background once
+    /// the node is initialized.
+    pub fn sweep_timeouts(self: &Arc<Self>) -> usize {
+        let now = Instant::now();
+        let mut resend = Vec::new();
+        let expired: Vec<RpcTimeout<S, B>> = {
+            let mut callbacks = lock(&self.callbacks);
+            let mut expired_ids = Vec::new();
+            for (msg_id, pending) in callbacks.iter_mut() {
+                let Some(timeout) = &mut pending.timeout else {
+                    continue;
+                };
+                if timeout.deadline > now {
+                    continue;
+                }
+                if timeout.attempts < timeout.policy.max_retries {
+                    timeout.attempts += 1;
+                    timeout.deadline = now + timeout.policy.timeout;
+                    resend.push((timeout.dest.clone(), timeout.line.clone()));
+                } else {
+                    expired_ids.push(*msg_id);
+                }
+            }
+            let expired = expired_ids
+                .into_iter()
+                .filter_map(|msg_id| callbacks.remove(&msg_id))
+                .filter_map(|pending| pending.timeout)
+                .collect::<Vec<_>>();
+            if !expired.is_empty() {
+                self.rpc_done.notify_all();
+            }
+            expired
+        };
+        // The callbacks lock is released before any I/O or user code runs
+        for (dest, line) in &resend {
+            if let Err(e) = self.write_line(dest, line) {
+                self.log_error(&format!("Failed to resend request: {}", e));
+            }
+        }
+        if !resend.is_empty() {
+            if let Err(e) = self.flush() {
+                self.log_error(&format!("Failed to flush resent requests: {}", e));
+            }
+        }
+        let _dispatching = Dispatching::enter();
+        for timeout in expired {
+            self.log_warn(&format!(
+                "Request to {} timed out after {} retries",
+                timeout.dest, timeout.attempts
+            ));
+            (timeout.on_timeout)(self);
+        }
+        resend.len()
+    }
+
+    /// Runs the callback registered for the request this message replies to,
+    /// passing an `error` reply as `Err`. Returns `true` if the message was a
+    /// reply, in which case it must not be dispatched further. Replies nobody
+    /// is waiting for are logged and dropped.
+    pub fn handle_reply(self: &Arc<Self>, message: &Message<B>) -> bool {
+        let Some(reply_to) = message.body.in_reply_to() else {
+            return false;
+        };
+        let pending = lock(&self.callbacks).remove(&reply_to);
+        if pending.is_some() {
+            self.rpc_done.notify_all();
+        }
+        match pending {
+            Some(pending) => {
+                let reply = match message.body.error() {
+                    Some(error) => Err(error),
+                    None => Ok(message),
+                };
+                let _dispatching = Dispatching::enter();
+                if let Err(e) = (pending.callback)(self, reply) {
+                    self.log_error(&format!("Error in callback: {}", e));
+                }
+            }
+            None if self.logs_at(LogLevel::Debug) => {
+                self.log_debug(&format!(
+                    "Dropping reply from {} to unknown request {}",
+                    message.src, reply_to
+                ));
+            }
+            None => {}
+        }
+        true
+    }
+
+    /// Same as [`Node::log_info`].
+    pub fn log(&self, text: &str) {
+        self.log_info(text);
+    }
+
+    pub fn log_debug(&self, text: &str) {
+        self.log_at(LogLevel::Debug, text);
+    }
+
+    pub fn log_info(&self, text: &str) {
+        self.log_at(LogLevel::Info, text);
+    }
+
+    pub fn log_warn(&self, text: &str) {
+        self.log_at(LogLevel::Warn, text);
+    }
+
+    pub fn log_error(&self, text: &str) {
+        self.log_at(LogLevel::Error, text);
+    }
+
+    /// Replaces the level from `MAELSTROM_LOG`; messages below `level` are
+    /// dropped from now on.
+    pub fn set_log_level(&self, level: LogLevel) {
+        self.log_level.store(level as u8, Ordering::Relaxed);
+    }
+
+    /// Whether messages at `level` are logged. Check it before formatting a
+    /// message that is logged often, such as one per request.
+    pub fn logs_at(&self, level: LogLevel) -> bool {
+        level as u8 >= self.log_level.load(Ordering::Relaxed)
+    }
+
+    /// Writes `text` to stderr, prefixed with a millisecond timestamp and the
+    /// level, if `level` is at least `MAELSTROM_LOG`. Stdout is left to the
+    /// protocol.
+    pub fn log_at(&self, level: LogLevel, text: &str) {
+        if !self.logs_at(level) {
+            return;
+        }
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis())
+            .unwrap_or_default();
+        let _ = writeln!(lock(&self.stderr), "{} {:<5} {}", millis, level, text);
+    }
+
+    fn write<T: Serialize>(&self, dest: &NodeId, body: T) -> Result<()> {
+        let jsonified = self.serialize(dest, body)?;
+        self.write_line(dest, &jsonified)
+    }
+
+    // A body that can't be serialized, e.g. a map with non-string keys,
+    // fails the send rather than the whole node
+    fn serialize<T: Serialize>(&self, dest: &NodeId, body: T) -> Result<String> {
+        let time = self.lamport.fetch_add(1, Ordering::SeqCst) + 1;
+        let message = Message {
+            src: self.node_id.clone(),
+            dest: dest.clone(),
+            lamport: self.stamp_lamport.load(Ordering::Relaxed).then_some(time),
+            body,
+        };
+        serde_json::to_string(&message).map_err(|e| NodeError::Serialize(e).into())
+    }
+
+    fn write_line(&self, dest: &NodeId, jsonified: &str) -> Result<()> {
+        self.write_lines(&[(dest, jsonified)], false)
+    }
+
+    // Each line is written whole under the lock, so concurrent senders never
+    // interleave partial JSON messages.
+    fn write_lines<D, L>(&self, lines: &[(D, L)], flush: bool) -> Result<()>
+    where
+        D: Borrow<NodeId>,
+        L: AsRef<str>,
+    {
+        self.check_protocol(lines);
+        if let Some(outbound) = self.outbound.get() {
+            outbound.push(
+                lines
+                    .iter()
+                    .map(|(dest, line)| (dest.borrow(), line.as_ref())),
+            );
+            self.sent.fetch_add(lines.len() as u64, Ordering::Relaxed);
+            if flush {
+                outbound.wait_drained()?;
+            }
+        } else {
+            let mut stdout = lock(&self.stdout);
+            for (_, line) in lines {
+                writeln!(stdout, "{}", line.as_ref())?;
+                self.sent.fetch_add(1, Ordering::Relaxed);
+            }
+            let unflushed = self.unflushed.fetch_add(lines.len(), Ordering::Relaxed) + lines.len();
+            if self.flush_due(unflushed, flush) {
+                stdout.flush()?;
+                self.unflushed.store(0, Ordering::Relaxed);
+            }
+        }
+        self.wrote(lines);
+        Ok(())
+    }
+
+    fn check_protocol<D, L>(&self, lines: &[(D, L)])
+    where
+        L: AsRef<str>,
+    {
+        #[cfg(debug_assertions)]
+        for (_, line) in lines {
+            if let Some(violation) = protocol_violation(line.as_ref()) {
+                self.log_error(&format!(
+                    "PROTOCOL VIOLATION: {} in {}",
+                    violation,
+                    line.as_ref()
+                ));
+            }
+        }
+        #[cfg(not(debug_assertions))]
+        let _ = lines;
+    }
+
+    /// Counts, records and logs lines once they are written or queued.
+    fn wrote<D, L>(&self, lines: &[(D, L)])
+    where
+        D: Borrow<NodeId>,
+        L: AsRef<str>,
+    {
+        self.count_bytes(lines);
+        for (_, line) in lines {
+            if let Some(recording) = &self.recording {
+                record(recording, line.as_ref());
+            }
+            if self.logs_at(LogLevel::Debug) {
+                self.log_debug(&format!("Sent: {}", line.as_ref()));
+            }
+        }
+    }
+
+    fn count_bytes<D, L>(&self, lines: &[(D, L)])
+    where
+        D: Borrow<NodeId>,
+        L: AsRef<str>,
+    {
+        let large_message = self.large_message.load(Ordering::Relaxed);
+        let mut bytes_sent = lock(&self.bytes_sent);
+        for (dest, line) in lines {
+            let line = line.as_ref();
+            let type_tag = line_type(line).unwrap_or("unknown");
+            match bytes_sent.get_mut(type_tag) {
+                Some(bytes) => *bytes += line.len() as u64 + 1,
+                None => {
+                    bytes_sent.insert(type_tag.to_string(), line.len() as u64 + 1);
+                }
+            }
+            if line.len() > large_message {
+                self.log_warn(&format!(
+                    "Sent a {} byte {} to {}",
+                    line.len(),
+                    type_tag,
+                    dest.borrow()
+                ));
+            }
+        }
+    }
+}
+
+fn reply_id<B: Body>(request: &Message<B>) -> Result<MsgId> {
+    request.body.msg_id().ok_or_else(|| {
+        format!(
+            "Cannot reply to a message from {} without msg_id",
+            request.src
+        )
+        .into()
+    })
+}
+
+/// Checks an outgoing line against what Maelstrom expects: replies (`*_ok`,
+/// `error`, and any other body with `in_reply_to`, such as `pong`) carry a
+/// numeric `in_reply_to`, everything else is a request and carries `msg_id`.
+/// Debug builds only.
+#[cfg(debug_assertions)]
+fn protocol_violation(line: &str) -> Option<String> {
+    let message: Message<serde_json::Value> = match serde_json::from_str(line) {
+        Ok(message) => message,
+        Err(e) => return Some(format!("not a message ({})", e)),
+    };
+    let Some(type_tag) = message.body.get("type").and_then(|t| t.as_str()) else {
+        return Some("body without a type".to_string());
+    };
+    let is_reply = type_tag.ends_with("_ok")
+        || type_tag == "error"
+        || message.body.get("in_reply_to").is_some();
+    let field = if is_reply { "in_reply_to" } else { "msg_id" };
+    match message.body.get(field) {
+        Some(id) if id.is_u64() => None,
+        _ => Some(format!("{} without a numeric {}", type_tag, field)),
+    }
+}
+
+// The `type` of a serialized message's body, found without parsing the
+// line. Bodies are internally tagged enums, whose tag serde writes first,
+// and quotes inside the strings before `body` are escaped.
+fn line_type(line: &str) -> Option<&str> {
+    const TAG: &str = r#""body":{"type":""#;
+    let start = line.find(TAG)? + TAG.len();
+    let len = line[start..].find('"')?;
+    Some(&line[start..start + len])
+}
+
+/// Splits the input into JSON values. Maelstrom sends exactly one per line,
+/// but several values on one line, or one value spread over several lines,
+/// are read correctly too.
+struct Frames<R> {
+    input: R,
+    // Text read but not yet split, ending in at most one incomplete value
+    pending: String,
+    ready: VecDeque<String>,
+    recording: Option<Recording>,
+    // Longest value `pending` may grow to, in bytes
+    max_line: usize,
+}
+
+impl<R: BufRead> Frames<R> {
+    fn new(input: R) -> Self {
+        Frames {
+            input,
+            pending: String::new(),
+            ready: VecDeque::new(),
+            recording: None,
+            max_line: DEFAULT_MAX_LINE_LENGTH,
+        }
+    }
+
+    /// The next complete value. Blocks for more input while the value so far
+    /// is incomplete.
+    fn next(&mut self) -> std::result::Result<String, ReceiveError> {
+        loop {
+            if let Some(frame) = self.ready.pop_front() {
+                if let Some(recording) = &self.recording {
+                    record(recording, &frame);
+                }
+                return Ok(frame);
+            }
+            let bytes = self.read_line()?;
+            // `read_line` reports EOF as zero bytes read. An incomplete value
+            // left over at that point can never be finished.
+            if bytes == 0 {
+                self.pending.clear();
+                return Err(ReceiveError::Eof);
+            }
+            self.split_pending()?;
+        }
+    }
+
+    /// Appends the next line to `pending` and returns how many bytes it had,
+    /// 0 at EOF. A line that would grow `pending` past `max_line` is read
+    /// to its end a buffer at a time and dropped along with `pending`.
+    fn read_line(&mut self) -> std::result::Result<usize, ReceiveError> {
+        let room = self.max_line.saturating_sub(self.pending.len());
+        let mut line = Vec::new();
+        let bytes = (&mut self.input)
+            .take(room as u64 + 1)
+            .read_until(b'\n', &mut line)
+            .map_err(ReceiveError::Io)?;
+        if bytes > room && !line.ends_with(b"\n") {
+            self.pending.clear();
+            self.skip_line().map_err(ReceiveError::Io)?;
+            return Err(ReceiveError::TooLong(self.max_line));
+        }
+        let line = String::from_utf8(line)
+            .map_err(|e| ReceiveError::Io(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+        self.pending.push_str(&line);
+        Ok(bytes)
+    }
+
+    /// Consumes input up to and including the next newline.
+    fn skip_line(&mut self) -> io::Result<()> {
+        loop {
+            let buffered = self.input.fill_buf()?;
+            let (used, done) = match buffered.iter().position(|&b| b == b'\n') {
+                Some(newline) => (newline + 1, true),
+                None => (buffered.len(), buffered.is_empty()),
+            };
+            self.input.consume(used);
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Moves every complete value out of `pending` into `ready`. On a syntax
+    /// error the whole pending text is dropped, so one bad line doesn't
+    /// poison the ones after it.
+    fn split_pending(&mut self) -> std::result::Result<(), ReceiveError> {
+        let mut values =
+            serde_json::Deserializer::from_str(&self.pending).into_iter::<serde::de::IgnoredAny>();
+        let mut consumed = 0;
+        loop {
+            match values.next() {
+                Some(Ok(_)) => {
+                    let end = values.byte_offset();
+                    self.ready
+                        .push_back(self.pending[consumed..end].trim_start().to_string());
+                    consumed = end;
+                }
+                // The last value continues on the next line
+                Some(Err(e)) if e.is_eof() => break,
+                Some(Err(e)) => {
+                    self.pending.clear();
+                    return Err(ReceiveError::Malformed(e));
+                }
+                None => {
+                    consumed = self.pending.len();
+                    break;
+                }
+            }
+        }
+        self.pending.drain(..consumed);
+        Ok(())
+    }
+}
+
+/// Stdout, or the TCP connection a `tcp://host:port` `setting` names.
+fn output_to(setting: Option<String>) -> Result<Output> {
+    let Some(setting) = setting else {
+        return Ok(Box::new(io::stdout()));
+    };
+    let address = setting
+        .strip_prefix("tcp://")
+        .ok_or_else(|| format!("Unsupported output '{}', expected tcp://host:port", setting))?;
+    let stream = TcpStream::connect(address)
+        .map_err(|e| format!("Failed to connect output to {}: {}", address, e))?;
+    Ok(Box::new(stream))
+}
+
+// A recording is a debugging aid; failing to write it must not stop the node
+fn record(recording: &Recording, line: &str) {
+    let _ = writeln!(lock(recording), "{}", line);
+}
+
+fn read_message<T: DeserializeOwned>(
+    frames: &mut Frames<impl BufRead>,
+) -> std::result::Result<Message<T>, ReceiveError> {
+    let frame = frames.next()?;
+    serde_json::from_str(&frame).map_err(ReceiveError::Malformed)
+}
+
+thread_local! {
+    // Whether this thread is running a handler, callback or timeout, so an
+    // RPC from it must not wait for a slot it may be the one to free
+    static DISPATCHING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Marks the current thread as dispatching until dropped.
+struct Dispatching {
+    was: bool,
+}
+
+impl Dispatching {
+    fn enter() -> Self {
+        Dispatching {
+            was: DISPATCHING.with(|dispatching| dispatching.replace(true)),
+        }
+    }
+}
+
+impl Drop for Dispatching {
+    fn drop(&mut self) {
+        DISPATCHING.with(|dispatching| dispatching.set(self.was));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::time::Instant;
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(tag = "type")]
+    enum TestBody {
+        #[serde(rename = "ping")]
+        Ping { msg_id: MsgId },
+        #[serde(rename = "pong")]
+        Pong { in_reply_to: MsgId },
+        #[serde(rename = "error")]
+        Error {
+            in_reply_to: MsgId,
+            code: ErrorCode,
+            text: String,
+        },
+    }
+
+    impl Body for TestBody {
+        fn type_tag(&self) -> &'static str {
+            match self {
+                Self::Ping { .. } => "ping",
+                Self::Pong { .. } => "pong",
+                Self::Error { .. } => "error",
+            }
+        }
+        fn msg_id(&self) -> Option<MsgId> {
+            match self {
+                Self::Ping { msg_id } => Some(*msg_id),
+                _ => None,
+            }
+        }
+        fn in_reply_to(&self) -> Option<MsgId> {
+            match self {
+                Self::Pong { in_reply_to } => Some(*in_reply_to),
+                Self::Error { in_reply_to, .. } => Some(*in_reply_to),
+                _ => None,
+            }
+        }
+    }
+
+    crate::impl_ack!(TestBody { Ping => Pong });
+
+    type TestNode = Node<AtomicU64, TestBody>;
+
+    #[test]
+    fn peers_exclude_this_node() {
+        let ids = ["n0", "n1", "n2"].map(NodeId::from).to_vec();
+        let node = TestNode::new(&NodeId::from("n1"), ids, AtomicU64::new(0));
+        assert_eq!(node.node_ids.len(), 3);
+        let peers: Vec<&str> = node.peers().map(NodeId::as_str).collect();
+        assert_eq!(peers, ["n0", "n2"]);
+    }
+
+    #[test]
+    fn every_runs_until_shutdown() {
+        let node = TestNode::new(&NodeId::from("n1"), vec![], AtomicU64::new(0));
+        let start = Instant::now();
+        let handle = node.every(
+            Duration::from_millis(20),
+            Box::new(|node| {
+                node.state.fetch_add(1, Ordering::SeqCst);
+            }),
+        );
+        thread::sleep(Duration::from_millis(210));
+        node.shutdown();
+        handle.join().unwrap();
+        let elapsed = start.elapsed().as_millis() as u64;
+
+        let count = node.state.load(Ordering::SeqCst);
+        assert!(count >= 5, "only {} invocations", count);
+        assert!(
+            count <= elapsed / 20,
+            "{} invocations in {}ms",
+            count,
+            elapsed
+        );
+    }
+
+    fn pong(in_reply_to: MsgId) -> Message<TestBody> {
+        Message {
+            src: NodeId::from("n2"),
+            dest: NodeId::from("n1"),
+            lamport: None,
+            body: TestBody::Pong { in_reply_to },
+        }
+    }
+
+    fn ping_with_timeout(node: &Arc<TestNode>, max_retries: u32) -> MsgId {
+        let policy = RetryPolicy {
+            timeout: Duration::from_millis(1),
+            max_retries,
+        };
+        node.rpc_with_timeout(
+            &NodeId::from("n2"),
+            |msg_id| TestBody::Ping { msg_id },
+            policy,
+            Box::new(|node, _reply| {
+                node.state.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }),
+            Box::new(|node| {
+                node.state.fetch_add(100, Ordering::SeqCst);
+            }),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn error_replies_reach_the_callback_as_err() {
+        let node = TestNode::new(&NodeId::from("n1"), vec![], AtomicU64::new(0));
+        let outcome = Arc::new(Mutex::new(Vec::new()));
+        for _ in 0..2 {
+            let seen = Arc::clone(&outcome);
+            node.rpc(
+                &NodeId::from("n2"),
+                |msg_id| TestBody::Ping { msg_id },
+                Box::new(move |_, reply| {
+                    lock(&seen).push(reply.map(|reply| reply.body.in_reply_to()));
+                    Ok(())
+                }),
+            )
+            .unwrap();
+        }
+
+        assert!(node.handle_reply(&Message {
+            src: NodeId::from("n2"),
+            dest: NodeId::from("n1"),
+            lamport: None,
+            body: TestBody::Error {
+                in_reply_to: 1,
+                code: ErrorCode::PreconditionFailed,
+                text: "expected 2, had 4".to_string(),
+            },
+        }));
+        assert!(node.handle_reply(&pong(2)));
+        assert_eq!(
+            *lock(&outcome),
+            [
+                Err(MaelstromError {
+                    code: ErrorCode::PreconditionFailed,
+                    text: "expected 2, had 4".to_string(),
+                }),
+                Ok(Some(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn rpcs_beyond_the_in_flight_cap_wait_for_a_reply() {
+        let node = TestNode::new(&NodeId::from("n1"), vec![], AtomicU64::new(0));
+        node.set_max_in_flight(2);
+        let ping = |node: &Arc<TestNode>| {
+            node.rpc(
+                &NodeId::from("n2"),
+                |msg_id| TestBody::Ping { msg_id },
+                Box::new(|_, _| Ok(())),
+            )
+            .unwrap()
+        };
+        ping(&node);
+        ping(&node);
+
+        let waiting = Arc::clone(&node);
+        let third = thread::spawn(move || ping(&waiting));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!third.is_finished());
+        assert_eq!(node.sent_count(), 2);
+
+        assert!(node.handle_reply(&pong(1)));
+        assert_eq!(third.join().unwrap(), 3);
+        assert_eq!(node.sent_count(), 3);
+    }
+
+    #[test]
+    fn rpcs_from_a_handler_fail_instead_of_waiting_on_a_full_cap() {
+        let node = TestNode::new(&NodeId::from("n1"), vec![], AtomicU64::new(0));
+        node.set_max_in_flight(1);
+        let outcomes = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&outcomes);
+        node.register(
+            "ping",
+            move |node: &Arc<TestNode>, _: &Message<TestBody>| {
+                for _ in 0..2 {
+                    let sent = node.rpc(
+                        &NodeId::from("n2"),
+                        |msg_id| TestBody::Ping { msg_id },
+                        Box::new(|_, _| Ok(())),
+                    );
+                    let failed = sent.err();
+                    lock(&seen).push(
+                        failed.and_then(|e| e.downcast_ref::<io::Error>().map(io::Error::kind)),
+                    );
+                }
+                Ok::<(), NodeError>(())
+            },
+        );
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        let handling = Arc::clone(&node);
+        thread::spawn(move || {
+            let ping = Message {
+                src: NodeId::from("c1"),
+                dest: NodeId::from("n1"),
+                lamport: None,
+                body: TestBody::Ping { msg_id: 7 },
+            };
+            done_tx.send(handling.handle(&ping).is_ok()).unwrap();
+        });
+
+        // Waiting would hang the handler, as only it could free the slot
+        assert_eq!(done_rx.recv_timeout(Duration::from_secs(2)), Ok(true));
+        assert_eq!(*lock(&outcomes), [None, Some(io::ErrorKind::WouldBlock)]);
+        assert_eq!(node.sent_count(), 1);
+    }
+
+    #[test]
+    fn rpc_with_timeout_resends_dropped_request() {
+        let node = TestNode::new(&NodeId::from("n1"), vec![], AtomicU64::new(0));
+        let msg_id = ping_with_timeout(&node, 3);
+
+        // The first reply is dropped, so the request goes out again
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(node.sweep_timeouts(), 1);
+
+        assert!(node.handle_reply(&pong(msg_id)));
+        assert_eq!(node.state.load(Ordering::SeqCst), 1);
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(node.sweep_timeouts(), 0);
+    }
+
+    #[test]
+    fn rpc_with_timeout_gives_up_after_max_retries() {
+        let node = TestNode::new(&NodeId::from("n1"), vec![], AtomicU64::new(0));
+        let msg_id = ping_with_timeout(&node, 2);
+
+        for _ in 0..2 {
+            thread::sleep(Duration::from_millis(5));
+            assert_eq!(node.sweep_timeouts(), 1);
+        }
+        assert_eq!(node.state.load(Ordering::SeqCst), 0);
+
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(node.sweep_timeouts(), 0);
+        assert_eq!(node.state.load(Ordering::SeqCst), 100);
+
+        // A late reply is dropped instead of firing the callback
+        assert!(node.handle_reply(&pong(msg_id)));
+        assert_eq!(node.state.load(Ordering::SeqCst), 100);
+    }
+
+    #[test]
+    fn read_message_stops_at_eof_and_skips_malformed_lines() {
+        let lines = concat!(
+            "not json\n",
+            r#"{"src":"c1","dest":"n1","body":{"type":"ping","msg_id":3}}"#,
+            "\n"
+        );
+        let mut input = Frames::new(lines.as_bytes());
+        assert!(matches!(
+            read_message::<TestBody>(&mut input),
+            Err(ReceiveError::Malformed(_))
+        ));
+        let message = read_message::<TestBody>(&mut input).unwrap();
+        assert_eq!(message.body.msg_id(), Some(3));
+        assert!(matches!(
+            read_message::<TestBody>(&mut input),
+            Err(ReceiveError::Eof)
+        ));
+    }
+
+    #[test]
+    fn read_message_recovers_from_invalid_utf8() {
+        let mut input = Frames::new(
+            &b"\xff\n{\"src\":\"c1\",\"dest\":\"n1\",\"body\":{\"type\":\"ping\",\"msg_id\":4}}\n"
+                [..],
+        );
+        assert!(matches!(
+            read_message::<TestBody>(&mut input),
+            Err(ReceiveError::Io(_))
+        ));
+        let message = read_message::<TestBody>(&mut input).unwrap();
+        assert_eq!(message.body.msg_id(), Some(4));
+    }
+
+    /// Inputs a buggy harness might send: random bytes, and valid messages
+    /// with bytes flipped, inserted or cut off. Seeded, so a failure repeats.
+    /// The `receive` fuzz target in `fuzz/` explores the same path further.
+    fn garbage(count: usize) -> Vec<Vec<u8>> {
+        let mut seed: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        let valid = concat!(
+            r#"{"src":"c1","dest":"n1","body":{"type":"ping","msg_id":4}}"#,
+            "\n",
+            r#"{"src":"n2","dest":"n1","body":{"type":"error","in_reply_to":1,"code":22,"text":"no"}}"#,
+            "\n"
+        )
+        .as_bytes();
+        let mut inputs = vec![
+            // Nested deeper than serde_json recurses, but under the line limit
+            format!("{}\n", "[".repeat(4000)).into_bytes(),
+            format!(
+                "{}\n",
+                r#"{"src":"c1","dest":"n1","body":{"type":"ping","msg_id":-1}}"#
+            )
+            .into_bytes(),
+            b"{} 1 \"x\" [] null\n{\n".to_vec(),
+        ];
+        for i in 0..count {
+            let input = if i % 4 == 0 {
+                let len = (next() % 256) as usize;
+                (0..len).map(|_| next() as u8).collect()
+            } else {
+                let mut input = valid.to_vec();
+                for _ in 0..1 + next() % 8 {
+                    let at = (next() as usize) % input.len();
+                    match next() % 3 {
+                        0 => input[at] = next() as u8,
+                        1 => input.insert(at, next() as u8),
+                        _ => input.truncate(at.max(1)),
+                    }
+                }
+                input
+            };
+            inputs.push(input);
+        }
+        inputs
+    }
+
+    /// `count` pings from c1 to n1, one per line.
+    fn pings(count: u64) -> Input {
+        let lines: String = (1..=count)
+            .map(|msg_id| {
+                format!(
+                    r#"{{"src":"c1","dest":"n1","body":{{"type":"ping","msg_id":{}}}}}"#,
+                    msg_id
+                ) + "\n"
+            })
+            .collect();
+        Box::new(Cursor::new(lines.into_bytes()))
+    }
+
+    #[test]
+    fn await_quiescence_waits_for_the_workers() {
+        let node = TestNode::with_io(
+            &NodeId::from("n1"),
+            vec![],
+            AtomicU64::new(0),
+            pings(20),
+            Box::new(io::sink()),
+        );
+        node.register("ping", |node: &Arc<TestNode>, _: &Message<TestBody>| {
+            thread::sleep(Duration::from_millis(5));
+            node.state.fetch_add(1, Ordering::SeqCst);
+            Ok::<(), NodeError>(())
+        });
+        let (queue, worker_queue) = std::sync::mpsc::channel();
+        let reader = {
+            let node = Arc::clone(&node);
+            thread::spawn(move || {
+                while let Ok(message) = node.receive() {
+                    queue.send(message).unwrap();
+                }
+            })
+        };
+        let worker = Arc::clone(&node);
+        thread::spawn(move || {
+            for message in worker_queue {
+                worker.dispatch(&message);
+            }
+        });
+        reader.join().unwrap();
+
+        assert!(node.await_quiescence(Duration::from_secs(5)));
+        assert_eq!(node.state.load(Ordering::SeqCst), 20);
+    }
+
+    #[test]
+    fn await_quiescence_times_out_while_a_message_is_undispatched() {
+        let node = TestNode::with_io(
+            &NodeId::from("n1"),
+            vec![],
+            AtomicU64::new(0),
+            pings(1),
+            Box::new(io::sink()),
+        );
+        assert!(node.await_quiescence(Duration::ZERO));
+        let ping = node.receive().unwrap();
+        assert!(!node.await_quiescence(Duration::from_millis(20)));
+        node.dispatch(&ping);
+        assert!(node.await_quiescence(Duration::ZERO));
+    }
+
+    /// The messages written to `output`.
+    fn written(output: &crate::testing::SharedBuffer) -> Vec<Message<TestBody>> {
+        String::from_utf8(lock(&output.0).clone())
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn lamport_stamps_order_a_send_before_what_it_causes() {
+        let n1_output = crate::testing::SharedBuffer::default();
+        let n1 = TestNode::with_io(
+            &NodeId::from("n1"),
+            vec![],
+            AtomicU64::new(0),
+            Box::new(io::empty()),
+            Box::new(n1_output.clone()),
+        );
+        n1.set_lamport_stamps(true);
+        for msg_id in 1..=5 {
+            n1.send(&NodeId::from("c1"), TestBody::Ping { msg_id })
+                .unwrap();
+        }
+        n1.send(&NodeId::from("n2"), TestBody::Ping { msg_id: 6 })
+            .unwrap();
+        n1.flush().unwrap();
+        let ping = written(&n1_output).pop().unwrap();
+        assert_eq!(ping.lamport, Some(6));
+
+        // n2 has sent less than n1, so its clock jumps past the ping's time
+        let n2_output = crate::testing::SharedBuffer::default();
+        let n2 = TestNode::with_io(
+            &NodeId::from("n2"),
+            vec![],
+            AtomicU64::new(0),
+            Box::new(Cursor::new(
+                format!("{}\n", serde_json::to_string(&ping).unwrap()).into_bytes(),
+            )),
+            Box::new(n2_output.clone()),
+        );
+        n2.set_lamport_stamps(true);
+        n2.send(&NodeId::from("c1"), TestBody::Ping { msg_id: 1 })
+            .unwrap();
+        let received = n2.receive().unwrap();
+        assert!(n2.lamport_time() > ping.lamport.unwrap());
+        n2.reply(&received, |in_reply_to| TestBody::Pong { in_reply_to })
+            .unwrap();
+        n2.flush().unwrap();
+        let pong = written(&n2_output).pop().unwrap();
+        assert!(pong.lamport > ping.lamport);
+        assert_eq!(n2.lamport_time(), pong.lamport.unwrap());
+    }
+
+    #[test]
+    fn unstamped_nodes_still_count_lamport_time() {
+        let output = crate::testing::SharedBuffer::default();
+        let node = TestNode::with_io(
+            &NodeId::from("n1"),
+            vec![],
+            AtomicU64::new(0),
+            Box::new(Cursor::new(
+                br#"{"src":"c1","dest":"n1","lamport":9,"body":{"type":"ping","msg_id":1}}"#
+                    .to_vec(),
+            )),
+            Box::new(output.clone()),
+        );
+        node.send(&NodeId::from("c1"), TestBody::Ping { msg_id: 1 })
+            .unwrap();
+        node.receive().unwrap();
+        node.flush().unwrap();
+        assert_eq!(node.lamport_time(), 10);
+        assert_eq!(written(&output)[0].lamport, None);
+    }
+
+    #[test]
+    fn receive_survives_arbitrary_input() {
+        for input in garbage(2000) {
+            let node = TestNode::with_io(
+                &NodeId::from("n1"),
+                vec![],
+                AtomicU64::new(0),
+                Box::new(Cursor::new(input)),
+                Box::new(io::sink()),
+            );
+            node.set_max_line_length(4096);
+            // Each call consumes input, so this reaches EOF
+            while !matches!(node.receive(), Err(ReceiveError::Eof)) {}
+        }
+    }
+
+    #[test]
+    fn read_message_skips_lines_over_the_limit() {
+        let ping = r#"{"src":"c1","dest":"n1","body":{"type":"ping","msg_id":5}}"#;
+        let oversized = format!(
+            r#"{{"src":"c1","dest":"n1","body":{{"type":"ping","msg_id":4,"padding":"{}"}}}}"#,
+            "x".repeat(100_000)
+        );
+        let lines = format!("{}\n{}\n", oversized, ping);
+        let mut input = Frames::new(lines.as_bytes());
+        input.max_line = ping.len() + 1;
+        assert!(matches!(
+            read_message::<TestBody>(&mut input),
+            Err(ReceiveError::TooLong(_))
+        ));
+        assert!(input.pending.capacity() < 1024);
+        let message = read_message::<TestBody>(&mut input).unwrap();
+        assert_eq!(message.body.msg_id(), Some(5));
+        assert!(matches!(
+            read_message::<TestBody>(&mut input),
+            Err(ReceiveError::Eof)
+        ));
+    }
+
+    #[test]
+    fn read_message_splits_and_joins_lines_into_messages() {
+        let lines = concat!(
+            r#"{"src":"c1","dest":"n1","body":{"type":"ping","msg_id":1}} "#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"ping","msg_id":2}}"#,
+            "\n",
+            r#"{"src":"c1","dest":"n1","#,
+            "\n",
+            r#""body":{"type":"ping","msg_id":3}}"#,
+            "\n"
+        );
+        let mut input = Frames::new(lines.as_bytes());
+        for msg_id in 1..=3 {
+            let message = read_message::<TestBody>(&mut input).unwrap();
+            assert_eq!(message.body.msg_id(), Some(msg_id));
+        }
+        assert!(matches!(
+            read_message::<TestBody>(&mut input),
+            Err(ReceiveError::Eof)
+        ));
+    }
+
+    #[test]
+    fn generated_msg_ids_start_at_one() {
+        let node = TestNode::new(&NodeId::from("n1"), vec![], AtomicU64::new(0));
+        let ids: Vec<MsgId> = (0..3).map(|_| node.get_next_msg_id()).collect();
+        assert_eq!(ids, [1, 2, 3]);
+    }
+
+    #[test]
+    fn is_duplicate_forgets_the_oldest_requests() {
+        let node = TestNode::new(&NodeId::from("n1"), vec![], AtomicU64::new(0));
+        node.set_dedup_capacity(2);
+        let ping = |msg_id| Message {
+            src: NodeId::from("c1"),
+            dest: NodeId::from("n1"),
+            lamport: None,
+            body: TestBody::Ping { msg_id },
+        };
+        assert!(!node.is_duplicate(&ping(1)));
+        assert!(node.is_duplicate(&ping(1)));
+        assert!(!node.is_duplicate(&ping(2)));
+        assert!(!node.is_duplicate(&ping(3)));
+        // 1 was evicted to make room for 3
+        assert!(!node.is_duplicate(&ping(1)));
+    }
+
+    #[test]
+    fn broadcast_to_peers_sends_each_peer_a_fresh_msg_id() {
+        let init = crate::testing::init_line("n1", &["n1", "n2", "n3"]);
+        let output = crate::testing::run_lines(
+            AtomicU64::new(0),
+            |node: &Arc<TestNode>| {
+                let sent = node.broadcast_to_peers(|msg_id| TestBody::Ping { msg_id });
+                assert_eq!(sent, 2);
+                node.flush().unwrap();
+            },
+            &[init.as_str()],
+        )
+        .unwrap();
+        assert_eq!(
+            output[1..],
+            [
+                r#"{"src":"n1","dest":"n2","body":{"type":"ping","msg_id":2}}"#,
+                r#"{"src":"n1","dest":"n3","body":{"type":"ping","msg_id":3}}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn unserializable_bodies_fail_the_send_instead_of_panicking() {
+        // JSON object keys must be strings, so the tuple keys can't be written
+        #[derive(Serialize, Deserialize)]
+        struct Keyed {
+            msg_id: MsgId,
+            by_pair: HashMap<(u8, u8), u8>,
+        }
+        impl Body for Keyed {
+            fn type_tag(&self) -> &'static str {
+                ""
+            }
+            fn msg_id(&self) -> Option<MsgId> {
+                Some(self.msg_id)
+            }
+            fn in_reply_to(&self) -> Option<MsgId> {
+                None
+            }
+        }
+
+        let node: Arc<Node<(), Keyed>> = Node::with_io(
+            &NodeId::from("n1"),
+            vec![],
+            (),
+            Box::new(io::empty()),
+            Box::new(io::sink()),
+        );
+        let body = Keyed {
+            msg_id: 1,
+            by_pair: HashMap::from([((1, 2), 3)]),
+        };
+        let sent = node.send(&NodeId::from("n2"), body);
+        let error = sent.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<NodeError>(),
+            Some(NodeError::Serialize(_))
+        ));
+        assert_eq!(node.sent_count(), 0);
+    }
+
+    #[test]
+    fn bytes_sent_accumulate_per_message_type() {
+        let (node, output) = flushing(FlushPolicy::Each);
+        ping(&node, 1);
+        ping(&node, 2);
+        node.send(&NodeId::from("c1"), TestBody::Pong { in_reply_to: 1 })
+            .unwrap();
+        node.flush().unwrap();
+        let written = String::from_utf8(lock(&output.0).clone()).unwrap();
+        let bytes_of = |type_tag: &str| {
+            written
+                .lines()
+                .filter(|line| line_type(line) == Some(type_tag))
+                .map(|line| line.len() as u64 + 1)
+                .sum::<u64>()
+        };
+        assert_eq!(
+            node.bytes_sent_by_type(),
+            BTreeMap::from([
+                ("ping".to_string(), bytes_of("ping")),
+                ("pong".to_string(), bytes_of("pong")),
+            ])
+        );
+        assert_eq!(
+            node.bytes_sent_by_type().values().sum::<u64>(),
+            written.len() as u64
+        );
+    }
+
+    #[test]
+    fn line_type_reads_the_body_type() {
+        assert_eq!(
+            line_type(r#"{"src":"n1","dest":"c1","body":{"type":"read_ok","in_reply_to":1}}"#),
+            Some("read_ok")
+        );
+        assert_eq!(line_type(r#"{"src":"n1","dest":"c1","body":{}}"#), None);
+    }
+
+    #[test]
+    fn flush_policies_parse_from_their_env_form() {
+        assert_eq!(FlushPolicy::parse("each"), Some(FlushPolicy::Each));
+        assert_eq!(FlushPolicy::parse("batch:8"), Some(FlushPolicy::Batch(8)));
+        assert_eq!(
+            FlushPolicy::parse("time:5ms"),
+            Some(FlushPolicy::Timed(Duration::from_millis(5)))
+        );
+        for invalid in [
+            "", "every", "batch:0", "batch:", "time:5", "time:0ms", "each:1",
+        ] {
+            assert_eq!(FlushPolicy::parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    /// A node writing to the returned buffer under `policy`.
+    fn flushing(policy: FlushPolicy) -> (Arc<TestNode>, crate::testing::SharedBuffer) {
+        let output = crate::testing::SharedBuffer::default();
+        let node = TestNode::with_io(
+            &NodeId::from("n1"),
+            vec![],
+            AtomicU64::new(0),
+            Box::new(io::empty()),
+            Box::new(output.clone()),
+        );
+        node.set_flush_policy(policy);
+        (node, output)
+    }
+
+    fn ping(node: &TestNode, msg_id: MsgId) {
+        node.send(&NodeId::from("c1"), TestBody::Ping { msg_id })
+            .unwrap();
+        node.flush().unwrap();
+    }
+
+    fn written_ids(output: &crate::testing::SharedBuffer) -> Vec<MsgId> {
+        written(output)
+            .iter()
+            .filter_map(|message| message.body.msg_id())
+            .collect()
+    }
+
+    #[test]
+    fn each_flush_writes_out_what_was_sent() {
+        let (node, output) = flushing(FlushPolicy::Each);
+        ping(&node, 1);
+        assert_eq!(written_ids(&output), [1]);
+        ping(&node, 2);
+        assert_eq!(written_ids(&output), [1, 2]);
+    }
+
+    #[test]
+    fn batched_flushes_wait_for_the_batch_to_fill() {
+        let (node, output) = flushing(FlushPolicy::Batch(3));
+        ping(&node, 1);
+        ping(&node, 2);
+        assert!(written_ids(&output).is_empty());
+        ping(&node, 3);
+        assert_eq!(written_ids(&output), [1, 2, 3]);
+        ping(&node, 4);
+        assert_eq!(written_ids(&output), [1, 2, 3]);
+
+        // Nothing is held back once the node shuts down
+        node.shutdown();
+        node.flush().unwrap();
+        assert_eq!(written_ids(&output), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn timed_flushes_write_out_on_the_timer() {
+        let (node, output) = flushing(FlushPolicy::Timed(Duration::from_millis(20)));
+        ping(&node, 1);
+        ping(&node, 2);
+        assert!(written_ids(&output).is_empty());
+        let start = Instant::now();
+        while written_ids(&output).is_empty() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(written_ids(&output), [1, 2]);
+        node.shutdown();
+    }
+
+    /// An output every write to fails, like a pipe whose reader is gone.
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    #[test]
+    fn failed_rpc_sends_free_their_slot() {
+        let node = TestNode::with_io(
+            &NodeId::from("n1"),
+            vec![],
+            AtomicU64::new(0),
+            Box::new(io::empty()),
+            Box::new(Broken),
+        );
+        node.set_flush_policy(FlushPolicy::Batch(1));
+        node.set_max_in_flight(1);
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            for _ in 0..3 {
+                let sent = node.rpc(
+                    &NodeId::from("n2"),
+                    |msg_id| TestBody::Ping { msg_id },
+                    Box::new(|_, _| Ok(())),
+                );
+                assert!(sent.is_err());
+            }
+            done_tx.send(lock(&node.callbacks).len()).unwrap();
+        });
+        // A leaked slot would block the second call for good
+        assert_eq!(done_rx.recv_timeout(Duration::from_secs(2)), Ok(0));
+    }
+
+    #[test]
+    fn failed_rpc_with_timeout_sends_free_their_slot() {
+        let node = TestNode::with_io(
+            &NodeId::from("n1"),
+            vec![],
+            AtomicU64::new(0),
+            Box::new(io::empty()),
+            Box::new(Broken),
+        );
+        node.set_flush_policy(FlushPolicy::Batch(1));
+        node.set_max_in_flight(1);
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            for _ in 0..3 {
+                let sent = node.rpc_with_timeout(
+                    &NodeId::from("n2"),
+                    |msg_id| TestBody::Ping { msg_id },
+                    RetryPolicy {
+                        timeout: Duration::from_secs(60),
+                        max_retries: 0,
+                    },
+                    Box::new(|_, _| Ok(())),
+                    Box::new(|_| {}),
+                );
+                assert!(sent.is_err());
+            }
+            done_tx.send(lock(&node.callbacks).len()).unwrap();
+        });
+        assert_eq!(done_rx.recv_timeout(Duration::from_secs(2)), Ok(0));
+    }
+
+    /// An output whose writes wait until it is opened, like a pipe nobody
+    /// reads from.
+    #[derive(Clone, Default)]
+    struct Stuck {
+        open: Arc<(Mutex<bool>, Condvar)>,
+        written: crate::testing::SharedBuffer,
+    }
+
+    impl Stuck {
+        fn open(&self) {
+            let (open, opened) = &*self.open;
+            *lock(open) = true;
+            opened.notify_all();
+        }
+    }
+
+    impl Write for Stuck {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let (open, opened) = &*self.open;
+            let mut is_open = lock(open);
+            while !*is_open {
+                is_open = opened.wait(is_open).unwrap();
+            }
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn try_send_gives_up_while_the_output_is_full() {
+        let output = Stuck::default();
+        let node = TestNode::with_io(
+            &NodeId::from("n1"),
+            vec![],
+            AtomicU64::new(0),
+            Box::new(io::empty()),
+            Box::new(output.clone()),
+        );
+        let timeout = Duration::from_millis(20);
+        let mut queued = 0;
+        let started = Instant::now();
+        let error = loop {
+            let ping = TestBody::Ping { msg_id: queued + 1 };
+            match node.try_send(&NodeId::from("c1"), ping, timeout) {
+                Ok(()) => queued += 1,
+                Err(e) => break e,
+            }
+            // The sender may take one batch before it gets stuck
+            assert!(queued <= 2 * TRY_SEND_QUEUE as u64);
+        };
+        assert_eq!(
+            error.downcast_ref::<io::Error>().map(io::Error::kind),
+            Some(io::ErrorKind::WouldBlock)
+        );
+        assert!(queued >= TRY_SEND_QUEUE as u64);
+        assert!(started.elapsed() >= timeout);
+
+        output.open();
+        node.flush().unwrap();
+        assert_eq!(
+            written_ids(&output.written),
+            (1..=queued).collect::<Vec<_>>()
+        );
+        assert_eq!(node.sent_count(), queued);
+    }
+
+    #[test]
+    fn ordered_output_keeps_each_destinations_messages_in_send_order() {
+        let output = crate::testing::SharedBuffer::default();
+        let node = TestNode::with_io(
+            &NodeId::from("n1"),
+            vec![],
+            AtomicU64::new(0),
+            Box::new(io::empty()),
+            Box::new(output.clone()),
+        )
+        .with_ordered_output();
+        let [n2, n3] = ["n2", "n3"].map(NodeId::from);
+        for msg_id in 1..=200 {
+            let dest = if msg_id % 3 == 0 { &n3 } else { &n2 };
+            node.send(dest, TestBody::Ping { msg_id }).unwrap();
+        }
+        node.flush().unwrap();
+
+        let written = String::from_utf8(lock(&output.0).clone()).unwrap();
+        let messages: Vec<Message<TestBody>> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(messages.len(), 200);
+        for dest in [&n2, &n3] {
+            let ids: Vec<MsgId> = messages
+                .iter()
+                .filter(|message| message.dest == *dest)
+                .filter_map(|message| message.body.msg_id())
+                .collect();
+            let expected: Vec<MsgId> = (1..=200)
+                .filter(|msg_id| (msg_id % 3 == 0) == (dest == &n3))
+                .collect();
+            assert_eq!(ids, expected);
+        }
+    }
+
+    #[test]
+    fn send_all_writes_one_line_per_message() {
+        let output = crate::testing::SharedBuffer::default();
+        let node = TestNode::with_io(
+            &NodeId::from("n1"),
+            vec![],
+            AtomicU64::new(0),
+            Box::new(io::empty()),
+            Box::new(output.clone()),
+        );
+        let messages: Vec<(NodeId, TestBody)> = ["n2", "n3"]
+            .into_iter()
+            .map(|dest| (NodeId::from(dest), TestBody::Ping { msg_id: 4 }))
+            .collect();
+        node.send_all(&messages).unwrap();
+
+        let written = String::from_utf8(lock(&output.0).clone()).unwrap();
+        assert_eq!(
+            written.lines().collect::<Vec<_>>(),
+            [
+                r#"{"src":"n1","dest":"n2","body":{"type":"ping","msg_id":4}}"#,
+                r#"{"src":"n1","dest":"n3","body":{"type":"ping","msg_id":4}}"#,
+            ]
+        );
+        assert_eq!(node.sent_count(), 2);
+    }
+
+    #[test]
+    fn rpc_all_with_timeout_runs_each_requests_own_callback() {
+        let node = TestNode::new(&NodeId::from("n1"), vec![], AtomicU64::new(0));
+        let requests = [1, 10]
+            .into_iter()
+            .map(|increment| {
+                let msg_id = node.get_next_msg_id();
+                let callback: Callback<AtomicU64, TestBody> = Box::new(move |node, _reply| {
+                    node.state.fetch_add(increment, Ordering::SeqCst);
+                    Ok(())
+                });
+                let on_timeout: TimeoutFn<AtomicU64, TestBody> = Box::new(|_| {});
+                (
+                    NodeId::from("n2"),
+                    TestBody::Ping { msg_id },
+                    callback,
+                    on_timeout,
+                )
+            })
+            .collect();
+        let policy = RetryPolicy {
+            timeout: Duration::from_secs(10),
+            max_retries: 0,
+        };
+        node.rpc_all_with_timeout(requests, policy).unwrap();
+
+        assert!(node.handle_reply(&pong(2)));
+        assert_eq!(node.state.load(Ordering::SeqCst), 10);
+        assert!(node.handle_reply(&pong(1)));
+        assert_eq!(node.state.load(Ordering::SeqCst), 11);
+    }
+
+    #[test]
+    fn init_rejects_unsupported_protocol_versions() {
+        let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":3,"node_id":"n1","node_ids":["n1"],"protocol_version":99}}"#;
+        let output = crate::testing::SharedBuffer::default();
+        let node = TestNode::init_with_io(
+            AtomicU64::new(0),
+            Box::new(io::Cursor::new(format!("{}\n", init).into_bytes())),
+            Box::new(output.clone()),
+        );
+        assert!(node.is_err());
+        let written = String::from_utf8(lock(&output.0).clone()).unwrap();
+        assert_eq!(
+            written.trim_end(),
+            r#"{"src":"n1","dest":"c0","body":{"type":"error","in_reply_to":3,"code":10,"text":"Protocol version 99 is not supported, only 1"}}"#
+        );
+    }
+
+    #[test]
+    fn replaying_a_recording_writes_the_same_messages() {
+        let path =
+            std::env::temp_dir().join(format!("maelstrom-record-{}.jsonl", std::process::id()));
+        let input = [
+            crate::testing::init_line("n1", &["n1", "n2"]),
+            r#"{"src":"c1","dest":"n1","body":{"type":"ping","msg_id":1}}"#.to_string(),
+            r#"{"src":"c2","dest":"n1","body":{"type":"ping","msg_id":2}}"#.to_string(),
+        ]
+        .join("\n");
+        let run = |node: Arc<TestNode>| {
+            node.register(
+                "ping",
+                |node: &Arc<TestNode>, message: &Message<TestBody>| {
+                    node.reply(message, |in_reply_to| TestBody::Pong { in_reply_to })
+                },
+            );
+            node.run();
+        };
+
+        let recorded = crate::testing::SharedBuffer::default();
+        let mut frames = Frames::new(Box::new(Cursor::new(input.into_bytes())) as Input);
+        frames.recording = Some(Arc::new(Mutex::new(LineWriter::new(
+            File::create(&path).unwrap(),
+        ))));
+        run(
+            TestNode::init_from_frames(AtomicU64::new(0), frames, Box::new(recorded.clone()))
+                .unwrap(),
+        );
+
+        let replayed = crate::testing::SharedBuffer::default();
+        run(TestNode::replay(AtomicU64::new(0), &path, Box::new(replayed.clone())).unwrap());
+        let recording = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let recorded = lock(&recorded.0).clone();
+        assert_eq!(recorded.split(|&b| b == b'\n').count(), 4);
+        assert_eq!(recorded, *lock(&replayed.0));
+        // Three messages in, three out
+        assert_eq!(recording.lines().count(), 6);
+    }
+
+    #[test]
+    fn ack_replies_with_the_matching_ok() {
+        let node = TestNode::new(&NodeId::from("n1"), vec![], AtomicU64::new(0));
+        let ping = |msg_id| Message {
+            src: NodeId::from("c1"),
+            dest: NodeId::from("n1"),
+            lamport: None,
+            body: TestBody::Ping { msg_id },
+        };
+        assert!(matches!(
+            ping(4).body.ack(4),
+            Some(TestBody::Pong { in_reply_to: 4 })
+        ));
+        assert!(pong(4).body.ack(4).is_none());
+        assert!(node.ack(&pong(4)).is_err());
+    }
+
+    #[test]
+    fn output_can_go_to_a_tcp_connection() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("tcp://{}", listener.local_addr().unwrap());
+        let output = output_to(Some(address)).unwrap();
+        let (client, _) = listener.accept().unwrap();
+
+        let node = TestNode::with_io(
+            &NodeId::from("n1"),
+            vec![],
+            AtomicU64::new(0),
+            Box::new(io::empty()),
+            output,
+        );
+        node.send(&NodeId::from("c1"), TestBody::Ping { msg_id: 1 })
+            .unwrap();
+        node.flush().unwrap();
+
+        let mut line = String::new();
+        BufReader::new(client).read_line(&mut line).unwrap();
+        assert_eq!(
+            line.trim_end(),
+            r#"{"src":"n1","dest":"c1","body":{"type":"ping","msg_id":1}}"#
+        );
+        assert!(output_to(Some("udp://127.0.0.1:1".to_string())).is_err());
+    }
+
+    #[test]
+    fn dispatch_routes_requests_by_type_tag() {
+        let node = TestNode::new(&NodeId::from("n1"), vec![], AtomicU64::new(0));
+        node.register(
+            "ping",
+            |node: &Arc<TestNode>, message: &Message<TestBody>| {
+                let msg_id = message.body.msg_id().ok_or("ping without msg_id")?;
+                node.state.store(msg_id, Ordering::SeqCst);
+                Ok::<_, &str>(())
+            },
+        );
+        node.dispatch(&Message {
+            src: NodeId::from("c1"),
+            dest: NodeId::from("n1"),
+            lamport: None,
+            body: TestBody::Ping { msg_id: 7 },
+        });
+        assert_eq!(node.state.load(Ordering::SeqCst), 7);
+    }
+
+    #[test]
+    fn init_ok_takes_the_first_msg_id() {
+        let init = crate::testing::init_line("n1", &["n1", "n2"]);
+        let output = crate::testing::run_lines(
+            AtomicU64::new(0),
+            |node: &Arc<TestNode>| {
+                let msg_id = node.get_next_msg_id();
+                node.send(&NodeId::from("n2"), TestBody::Ping { msg_id })
+                    .unwrap();
+                node.flush().unwrap();
+            },
+            &[init.as_str()],
+        )
+        .unwrap();
+        let init_ok: Message<InitBody> = serde_json::from_str(&output[0]).unwrap();
+        assert!(matches!(
+            init_ok.body,
+            InitBody::InitOk {
+                msg_id: 1,
+                in_reply_to: 0
+            }
+        ));
+        assert_eq!(
+            output[1],
+            r#"{"src":"n1","dest":"n2","body":{"type":"ping","msg_id":2}}"#
+        );
+    }
+
+    #[test]
+    fn messages_for_other_nodes_are_dropped() {
+        let init = crate::testing::init_line("n1", &["n1", "n2"]);
+        let output = crate::testing::run_lines(
+            AtomicU64::new(0),
+            |_: &Arc<TestNode>| {},
+            &[
+                init.as_str(),
+                r#"{"src":"c1","dest":"n2","body":{"type":"ping","msg_id":1}}"#,
+            ],
+        )
+        .unwrap();
+        // Only init_ok, not even a not-supported error
+        assert_eq!(output.len(), 1, "{:?}", output);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn protocol_violation_flags_missing_ids() {
+        let line = |body: &str| format!(r#"{{"src":"n1","dest":"c1","body":{}}}"#, body);
+        assert_eq!(
+            protocol_violation(&line(r#"{"type":"echo_ok","in_reply_to":1}"#)),
+            None
+        );
+        assert_eq!(
+            protocol_violation(&line(r#"{"type":"gossip","msg_id":2}"#)),
+            None
+        );
+        // A reply without an `_ok` suffix, like a heartbeat's pong
+        assert_eq!(
+            protocol_violation(&line(r#"{"type":"pong","in_reply_to":3}"#)),
+            None
+        );
+        assert!(protocol_violation(&line(r#"{"type":"pong","in_reply_to":"3"}"#)).is_some());
+        assert!(protocol_violation(&line(r#"{"type":"echo_ok","msg_id":1}"#)).is_some());
+        assert!(protocol_violation(&line(r#"{"type":"error","code":13}"#)).is_some());
+        assert!(protocol_violation(&line(r#"{"type":"gossip"}"#)).is_some());
+    }
+
+    #[test]
+    fn log_level_can_be_changed_after_init() {
+        let node = TestNode::new(&NodeId::from("n1"), vec![], AtomicU64::new(0));
+        node.set_log_level(LogLevel::Debug);
+        assert!(node.logs_at(LogLevel::Debug));
+        node.set_log_level(LogLevel::Info);
+        assert!(!node.logs_at(LogLevel::Debug));
+        assert!(node.logs_at(LogLevel::Info));
+        assert!(node.logs_at(LogLevel::Error));
+        node.set_log_level(LogLevel::Error);
+        assert!(!node.logs_at(LogLevel::Warn));
+    }
+
+    #[test]
+    fn handle_reports_handler_errors() {
+        let node = TestNode::new(&NodeId::from("n1"), vec![], AtomicU64::new(0));
+        node.register("ping", |_: &Arc<TestNode>, _: &Message<TestBody>| {
+            Err::<(), _>("no pong today")
+        });
+        let error = node
+            .handle(&Message {
+                src: NodeId::from("c1"),
+                dest: NodeId::from("n1"),
+                lamport: None,
+                body: TestBody::Ping { msg_id: 1 },
+            })
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Failed to handle ping: no pong today");
+    }
+
+    #[test]
+    fn replying_handlers_have_their_replies_sent() {
+        let output = crate::testing::SharedBuffer::default();
+        let node = TestNode::with_io(
+            &NodeId::from("n1"),
+            vec![],
+            AtomicU64::new(0),
+            Box::new(io::empty()),
+            Box::new(output.clone()),
+        );
+        node.register_replying("ping", |_: &Arc<TestNode>, message: &Message<TestBody>| {
+            let in_reply_to = message.body.msg_id().ok_or("ping without msg_id")?;
+            Ok::<_, &str>(vec![(message.src.clone(), TestBody::Pong { in_reply_to })])
+        });
+        node.handle(&Message {
+            src: NodeId::from("c1"),
+            dest: NodeId::from("n1"),
+            lamport: None,
+            body: TestBody::Ping { msg_id: 7 },
+        })
+        .unwrap();
+        node.flush().unwrap();
+
+        let replies = written(&output);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].dest, NodeId::from("c1"));
+        assert_eq!(replies[0].body.in_reply_to(), Some(7));
+    }
+
+    #[test]
+    fn panicking_handler_does_not_poison_later_requests() {
+        let node = Node::<Mutex<Vec<MsgId>>, TestBody>::new(
+            &NodeId::from("n1"),
+            vec![],
+            Mutex::new(Vec::new()),
+        );
+        node.register(
+            "ping",
+            |node: &Arc<Node<_, _>>, message: &Message<TestBody>| {
+                let msg_id = message.body.msg_id().ok_or("ping without msg_id")?;
+                let mut seen = lock(&node.state);
+                seen.push(msg_id);
+                if msg_id == 1 {
+                    panic!("handler panicked while holding the state lock");
+                }
+                Ok::<_, &str>(())
+            },
+        );
+        for msg_id in [1, 2] {
+            node.dispatch(&Message {
+                src: NodeId::from("c1"),
+                dest: NodeId::from("n1"),
+                lamport: None,
+                body: TestBody::Ping { msg_id },
+            });
+        }
+        assert!(node.state.is_poisoned());
+        assert_eq!(*lock(&node.state), [1, 2]);
+    }
+}
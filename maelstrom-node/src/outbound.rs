@@ -0,0 +1,154 @@
+//! Per-destination output queues behind [`crate::Node::with_ordered_output`]
+//! and [`crate::Node::try_send`].
+
+use crate::message::NodeId;
+use crate::sync::lock;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Lines waiting to be written, one FIFO queue per destination. A single
+/// sender thread drains them, so lines to one destination are written in
+/// the order they were queued no matter which thread queued them.
+pub(crate) struct Outbound {
+    queues: Mutex<Queues>,
+    // Signalled when lines are queued, taken and written
+    changed: Condvar,
+    // How many lines `push_within` lets wait at once
+    capacity: usize,
+}
+
+#[derive(Default)]
+struct Queues {
+    lines: HashMap<NodeId, VecDeque<String>>,
+    // Destinations with queued lines, in the order they got their first one
+    ready: VecDeque<NodeId>,
+    // Lines across all queues
+    queued: usize,
+    // The sender took lines that aren't written yet
+    writing: bool,
+    // The last write error, reported by the next `wait_drained`
+    failed: Option<io::Error>,
+}
+
+impl Outbound {
+    /// Creates the queues and starts the thread writing them to `output`,
+    /// which runs for as long as the process. `capacity` only limits
+    /// `push_within`; `push` always queues.
+    pub(crate) fn start<W: Write + Send + 'static>(
+        output: Arc<Mutex<W>>,
+        capacity: usize,
+    ) -> Arc<Self> {
+        let outbound = Arc::new(Outbound {
+            queues: Mutex::new(Queues::default()),
+            changed: Condvar::new(),
+            capacity,
+        });
+        let sender = Arc::clone(&outbound);
+        thread::spawn(move || sender.send_queued(&output));
+        outbound
+    }
+
+    pub(crate) fn push<'a>(&self, lines: impl IntoIterator<Item = (&'a NodeId, &'a str)>) {
+        let mut queues = lock(&self.queues);
+        for (dest, line) in lines {
+            queues.push(dest, line);
+        }
+        self.changed.notify_all();
+    }
+
+    /// Queues `line` once fewer than `capacity` lines are waiting. Fails
+    /// with `WouldBlock` if there is still no room after `timeout`, e.g.
+    /// because the sender is stuck writing to a full pipe.
+    pub(crate) fn push_within(
+        &self,
+        dest: &NodeId,
+        line: &str,
+        timeout: Duration,
+    ) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut queues = lock(&self.queues);
+        while queues.queued >= self.capacity {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!(
+                        "{} messages are already waiting to be written",
+                        queues.queued
+                    ),
+                ));
+            }
+            queues = self
+                .changed
+                .wait_timeout(queues, left)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        queues.push(dest, line);
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// Blocks until everything queued so far is written and flushed.
+    pub(crate) fn wait_drained(&self) -> io::Result<()> {
+        let mut queues = lock(&self.queues);
+        while !queues.ready.is_empty() || queues.writing {
+            queues = self.wait(queues);
+        }
+        queues.failed.take().map_or(Ok(()), Err)
+    }
+
+    fn send_queued<W: Write>(&self, output: &Mutex<W>) {
+        let mut queues = lock(&self.queues);
+        loop {
+            let Some(dest) = queues.ready.pop_front() else {
+                queues = self.wait(queues);
+                continue;
+            };
+            let lines = queues.lines.remove(&dest).unwrap_or_default();
+            queues.queued -= lines.len();
+            queues.writing = true;
+            // Their room is free again
+            self.changed.notify_all();
+            drop(queues);
+
+            let written = write_lines(output, &lines);
+
+            queues = lock(&self.queues);
+            queues.writing = false;
+            if let Err(e) = written {
+                queues.failed = Some(e);
+            }
+            self.changed.notify_all();
+        }
+    }
+
+    fn wait<'a>(&self, queues: MutexGuard<'a, Queues>) -> MutexGuard<'a, Queues> {
+        self.changed
+            .wait(queues)
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Queues {
+    fn push(&mut self, dest: &NodeId, line: &str) {
+        let queue = self.lines.entry(dest.clone()).or_default();
+        let was_empty = queue.is_empty();
+        queue.push_back(line.to_string());
+        if was_empty {
+            self.ready.push_back(dest.clone());
+        }
+        self.queued += 1;
+    }
+}
+
+fn write_lines<W: Write>(output: &Mutex<W>, lines: &VecDeque<String>) -> io::Result<()> {
+    let mut output = lock(output);
+    for line in lines {
+        writeln!(output, "{}", line)?;
+    }
+    output.flush()
+}
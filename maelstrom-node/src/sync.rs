@@ -0,0 +1,11 @@
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Locks `mutex`, recovering the guard if a thread panicked while holding it.
+///
+/// With plain `lock()` one panicking handler poisons the mutex and every later
+/// caller fails, so the node stops acknowledging anything. Use this for state
+/// that a panic cannot leave half updated, such as sets that are only
+/// inserted into.
+pub fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,303 @@
+//! In-process harness for exercising handlers without launching Maelstrom.
+
+use crate::message::{Body, Message, NodeId};
+use crate::node::Node;
+use crate::sync::lock;
+use crate::Result;
+use std::collections::{HashSet, VecDeque};
+use std::env;
+use std::io::{self, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::sync::{Arc, Mutex};
+
+/// An `init` message for `node_id` in a cluster of `node_ids`.
+pub fn init_line(node_id: &str, node_ids: &[&str]) -> String {
+    serde_json::json!({
+        "src": "c0",
+        "dest": node_id,
+        "body": {"type": "init", "msg_id": 0, "node_id": node_id, "node_ids": node_ids},
+    })
+    .to_string()
+}
+
+/// Feeds `lines` to a node as if they arrived on stdin, runs it until the
+/// input is exhausted and returns every line it wrote, `init_ok` included.
+/// Messages are handled one at a time with [`Node::run`], so replies come
+/// back in the order of the requests even for a binary that normally
+/// dispatches from a worker pool.
+///
+/// The first line must be an `init` message, see [`init_line`]. `setup` runs
+/// on the initialized node before the remaining lines are dispatched, which
+/// is where a challenge registers its handlers.
+pub fn run_lines<S, B>(
+    state: S,
+    setup: impl FnOnce(&Arc<Node<S, B>>),
+    lines: &[&str],
+) -> Result<Vec<String>>
+where
+    S: Send + Sync + 'static,
+    B: Body + Send + 'static,
+{
+    let input: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+    let output = SharedBuffer::default();
+    let node = Node::init_with_io(
+        state,
+        Box::new(Cursor::new(input.into_bytes())),
+        Box::new(output.clone()),
+    )?;
+    setup(&node);
+    node.run();
+    let written = String::from_utf8(lock(&output.0).clone())?;
+    Ok(written.lines().map(str::to_string).collect())
+}
+
+/// How a run of the real Maelstrom went, see [`run_maelstrom`].
+#[derive(Debug)]
+pub struct MaelstromRun {
+    pub status: ExitStatus,
+    /// The top-level `:valid?` from `results.edn`, `None` if Maelstrom
+    /// didn't get far enough to write one.
+    pub valid: Option<bool>,
+    /// Maelstrom's stdout and stderr, for the assertion message.
+    pub output: String,
+}
+
+/// The Maelstrom launcher to run: MAELSTROM_BIN if set, otherwise
+/// `maelstrom` if it is on PATH.
+pub fn maelstrom_binary() -> Option<PathBuf> {
+    if let Some(configured) = env::var_os("MAELSTROM_BIN") {
+        return Some(PathBuf::from(configured));
+    }
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join("maelstrom"))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Runs `maelstrom test -w <workload> --bin <bin> <args>` in `dir`, where
+/// Maelstrom writes its `store`. `None` if Maelstrom isn't installed, so
+/// integration tests can skip instead of failing on machines without it.
+pub fn run_maelstrom(
+    workload: &str,
+    bin: &str,
+    args: &[&str],
+    dir: &Path,
+) -> Option<Result<MaelstromRun>> {
+    let maelstrom = maelstrom_binary()?;
+    Some(run_maelstrom_at(&maelstrom, workload, bin, args, dir))
+}
+
+fn run_maelstrom_at(
+    maelstrom: &Path,
+    workload: &str,
+    bin: &str,
+    args: &[&str],
+    dir: &Path,
+) -> Result<MaelstromRun> {
+    std::fs::create_dir_all(dir)?;
+    let output = Command::new(maelstrom)
+        .current_dir(dir)
+        .args(["test", "-w", workload, "--bin", bin])
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run {}: {}", maelstrom.display(), e))?;
+    let results = std::fs::read_to_string(dir.join("store/latest/results.edn")).ok();
+    Ok(MaelstromRun {
+        status: output.status,
+        valid: results.as_deref().and_then(top_level_validity),
+        output: format!(
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ),
+    })
+}
+
+/// The verdict in a `results.edn`. Each checker nests its own `:valid?`;
+/// the top-level one, which combines them, is written last.
+fn top_level_validity(results: &str) -> Option<bool> {
+    let (_, verdict) = results.rsplit_once(":valid?")?;
+    match verdict.split_whitespace().next()?.trim_end_matches('}') {
+        "true" => Some(true),
+        "false" | ":unknown" => Some(false),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuffer(pub(crate) Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        lock(&self.0).extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A cluster of nodes in one process, for testing how they converge.
+/// Nothing runs on its own: every message a node writes is queued, and
+/// [`Network::deliver_all`] dispatches the queue until it is empty.
+/// Periodic work such as gossip runs when the test calls [`Network::tick`].
+///
+/// Messages between nodes on different sides of a [`Network::partition`]
+/// are dropped. Messages for anyone else, such as clients, are kept for
+/// [`Network::take_outbox`].
+pub struct Network<S, B> {
+    nodes: Vec<Arc<Node<S, B>>>,
+    in_flight: Arc<Mutex<VecDeque<String>>>,
+    cut: HashSet<(NodeId, NodeId)>,
+    outbox: Vec<String>,
+    dropped: usize,
+}
+
+impl<S, B: Body> Network<S, B> {
+    /// Nodes `n0` to `n{node_count - 1}`, each with its own `state()`,
+    /// passed to `setup` to register handlers. No `init` is exchanged.
+    pub fn new(node_count: usize, state: impl Fn() -> S, setup: impl Fn(&Arc<Node<S, B>>)) -> Self {
+        let ids: Vec<NodeId> = (0..node_count)
+            .map(|i| NodeId::new(format!("n{}", i)))
+            .collect();
+        let in_flight = Arc::new(Mutex::new(VecDeque::new()));
+        let nodes = ids
+            .iter()
+            .map(|id| {
+                let node = Node::with_io(
+                    id,
+                    ids.clone(),
+                    state(),
+                    Box::new(io::empty()),
+                    Box::new(Lines {
+                        partial: Vec::new(),
+                        sink: Arc::clone(&in_flight),
+                    }),
+                );
+                setup(&node);
+                node
+            })
+            .collect();
+        Network {
+            nodes,
+            in_flight,
+            cut: HashSet::new(),
+            outbox: Vec::new(),
+            dropped: 0,
+        }
+    }
+
+    pub fn nodes(&self) -> &[Arc<Node<S, B>>] {
+        &self.nodes
+    }
+
+    /// Queues a message, e.g. a client request, for delivery.
+    pub fn send(&self, line: &str) {
+        lock(&self.in_flight).push_back(line.to_string());
+    }
+
+    /// Drops every message between a node in `left` and one in `right`
+    /// until [`Network::heal`].
+    pub fn partition(&mut self, left: &[&str], right: &[&str]) {
+        for a in left {
+            for b in right {
+                self.cut.insert((NodeId::from(*a), NodeId::from(*b)));
+                self.cut.insert((NodeId::from(*b), NodeId::from(*a)));
+            }
+        }
+    }
+
+    pub fn heal(&mut self) {
+        self.cut.clear();
+    }
+
+    /// Messages dropped by partitions so far.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Runs `f` on every node, then the RPC timeout sweep, as the
+    /// background threads of a running node would.
+    pub fn tick(&mut self, f: impl Fn(&Arc<Node<S, B>>)) {
+        for node in &self.nodes {
+            f(node);
+            node.sweep_timeouts();
+            node.flush().expect("Writing to the network never fails");
+        }
+    }
+
+    /// Delivers queued messages, and whatever handling them sends, until
+    /// nothing is left. Returns how many were dispatched to nodes.
+    pub fn deliver_all(&mut self) -> usize {
+        let mut delivered = 0;
+        loop {
+            let Some(line) = lock(&self.in_flight).pop_front() else {
+                return delivered;
+            };
+            let message: Message<B> = serde_json::from_str(&line)
+                .unwrap_or_else(|e| panic!("Unparseable message {}: {}", line, e));
+            let Some(node) = self.nodes.iter().find(|node| node.node_id == message.dest) else {
+                self.outbox.push(line);
+                continue;
+            };
+            if self
+                .cut
+                .contains(&(message.src.clone(), message.dest.clone()))
+            {
+                self.dropped += 1;
+                continue;
+            }
+            node.dispatch(&message);
+            node.flush().expect("Writing to the network never fails");
+            delivered += 1;
+            assert!(delivered < 1_000_000, "Messages kept flowing");
+        }
+    }
+
+    /// Messages delivered to anyone but the nodes, oldest first.
+    pub fn take_outbox(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.outbox)
+    }
+}
+
+/// Queues each complete line written to it.
+struct Lines {
+    partial: Vec<u8>,
+    sink: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl Write for Lines {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.partial.extend_from_slice(buf);
+        while let Some(end) = self.partial.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line[..end]).into_owned();
+            lock(&self.sink).push_back(line);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_verdict_is_the_top_level_valid() {
+        let results = r#"{:perf {:valid? true},
+ :stats {:valid? true,
+         :count 5},
+ :workload {:valid? false, :errors ["missing"]},
+ :net {:valid? true},
+ :valid? false}
+"#;
+        assert_eq!(top_level_validity(results), Some(false));
+        assert_eq!(top_level_validity(" :valid? true}\n"), Some(true));
+        assert_eq!(top_level_validity("{:stats {}}"), None);
+    }
+}
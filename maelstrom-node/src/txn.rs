@@ -0,0 +1,133 @@
+//! Micro-operations of Maelstrom's transactional workloads.
+//!
+//! On the wire each operation is a three-element array such as
+//! `["r", 1, null]` or `["w", 1, 6]`, which serde cannot derive onto an enum.
+
+use serde::de::{self, SeqAccess, Visitor};
+use serde::ser::SerializeTuple;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+pub type Key = u64;
+pub type Value = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxnOp {
+    /// `value` is `None` in a request and holds the value read, if any, in
+    /// the reply.
+    Read {
+        key: Key,
+        value: Option<Value>,
+    },
+    Write {
+        key: Key,
+        value: Value,
+    },
+}
+
+impl TxnOp {
+    pub fn key(&self) -> Key {
+        match self {
+            TxnOp::Read { key, .. } | TxnOp::Write { key, .. } => *key,
+        }
+    }
+}
+
+impl Serialize for TxnOp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(3)?;
+        match self {
+            TxnOp::Read { key, value } => {
+                tuple.serialize_element("r")?;
+                tuple.serialize_element(key)?;
+                tuple.serialize_element(value)?;
+            }
+            TxnOp::Write { key, value } => {
+                tuple.serialize_element("w")?;
+                tuple.serialize_element(key)?;
+                tuple.serialize_element(value)?;
+            }
+        }
+        tuple.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for TxnOp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_tuple(3, TxnOpVisitor)
+    }
+}
+
+struct TxnOpVisitor;
+
+impl<'de> Visitor<'de> for TxnOpVisitor {
+    type Value = TxnOp;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a [op, key, value] micro-op array")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<TxnOp, A::Error> {
+        let op: String = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let key: Key = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        let value: Option<Value> = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+        if seq.next_element::<de::IgnoredAny>()?.is_some() {
+            return Err(de::Error::invalid_length(4, &self));
+        }
+        match (op.as_str(), value) {
+            ("r", value) => Ok(TxnOp::Read { key, value }),
+            ("w", Some(value)) => Ok(TxnOp::Write { key, value }),
+            ("w", None) => Err(de::Error::custom("write without a value")),
+            (op, _) => Err(de::Error::unknown_variant(op, &["r", "w"])),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn micro_ops_round_trip_as_arrays() {
+        let ops: Vec<TxnOp> = serde_json::from_str(r#"[["r",1,null],["w",1,6]]"#).unwrap();
+        assert_eq!(
+            ops,
+            [
+                TxnOp::Read {
+                    key: 1,
+                    value: None
+                },
+                TxnOp::Write { key: 1, value: 6 }
+            ]
+        );
+        assert_eq!(
+            serde_json::to_string(&ops).unwrap(),
+            r#"[["r",1,null],["w",1,6]]"#
+        );
+
+        // Reads come in empty and go out with the value filled in
+        let read = TxnOp::Read {
+            key: 1,
+            value: Some(6),
+        };
+        assert_eq!(serde_json::to_string(&read).unwrap(), r#"["r",1,6]"#);
+    }
+
+    #[test]
+    fn malformed_micro_ops_are_rejected() {
+        for json in [
+            r#"["w",1,null]"#,
+            r#"["x",1,2]"#,
+            r#"["r",1]"#,
+            r#"["r",1,null,4]"#,
+        ] {
+            assert!(serde_json::from_str::<TxnOp>(json).is_err(), "{}", json);
+        }
+    }
+}